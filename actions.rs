@@ -0,0 +1,173 @@
+use nix::errno::Errno;
+use nix::libc;
+use nix::sys::signal::{self, Signal};
+use nix::unistd::{self, Pid};
+use nix_scope::host::Host;
+use nix_scope::procfs::{self, ProcTable};
+use nix_scope::Build;
+use std::fmt;
+
+/// Something to do to the selected build, picked in the TUI
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+	Signal(Signal),
+	Priority(Priority),
+	/// Back to what the build had before its priority was first changed
+	RestorePriority,
+}
+
+/// Sends `signal` to every process of a build, describing what happened for the status line
+///
+/// `procs` should be freshly scanned; pids that were reused since are skipped.
+pub fn signal_build(host: &Host, procs: &ProcTable, build: &Build, signal: Signal) -> String {
+	let (mut sent, mut exited) = (0, 0);
+	let mut failed: Vec<(i32, Errno)> = Vec::new();
+	for &pid in &build.pids {
+		if !still_running(host, procs, pid) {
+			exited += 1;
+			continue;
+		}
+		match signal::kill(Pid::from_raw(pid), signal) {
+			Ok(()) => sent += 1,
+			// gone since the last refresh
+			Err(Errno::ESRCH) => exited += 1,
+			Err(err) => failed.push((pid, err)),
+		}
+	}
+
+	let mut message = format!(
+		"sent {} to {} of {} pids of ({})",
+		signal.as_str(),
+		sent,
+		build.pids.len(),
+		build.user
+	);
+	if exited > 0 {
+		message.push_str(&format!(", {} already exited", exited));
+	}
+	if let Some(&(pid, err)) = failed.first() {
+		message.push_str(&format!(", {} failed (pid {}: {})", failed.len(), pid, describe(err)));
+	}
+	message
+}
+
+/// Whether `pid` is still the process `procs` found, rather than gone or reused by another one
+///
+/// Never for pids read from a captured tree, which belong to other processes or none at all here.
+fn still_running(host: &Host, procs: &ProcTable, pid: i32) -> bool {
+	host.is_local()
+		&& procs
+			.get(pid)
+			.is_some_and(|process| procfs::start_ticks(&host.proc_root, pid) == Some(process.start_ticks))
+}
+
+/// I/O scheduling class, as set by `ionice -c`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoClass {
+	/// Follows the cpu nice value, the default
+	None,
+	/// Served before everything else, priority 0 to 7 like best-effort
+	RealTime(u8),
+	/// Priority 0 to 7, lower is served first
+	BestEffort(u8),
+	/// Only served when nothing else wants the disk
+	Idle,
+}
+
+/// Cpu and I/O priority for all of a build's processes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Priority {
+	/// -20 to 19, higher yields to everything else
+	pub nice: i32,
+	pub io_class: IoClass,
+}
+
+impl fmt::Display for Priority {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "nice {}, io ", self.nice)?;
+		match self.io_class {
+			IoClass::None => write!(f, "by nice"),
+			IoClass::RealTime(level) => write!(f, "realtime {}", level),
+			IoClass::BestEffort(level) => write!(f, "best-effort {}", level),
+			IoClass::Idle => write!(f, "idle"),
+		}
+	}
+}
+
+/// Sets the nice value and I/O class of every thread of a build
+///
+/// `procs` should be freshly scanned; pids that were reused since are skipped.
+/// Returns the first failure other than a process having exited, after trying all of them.
+pub fn set_build_priority(host: &Host, procs: &ProcTable, build: &Build, priority: Priority) -> Result<(), String> {
+	// both are per thread on Linux, and new threads and children inherit them from whoever creates them
+	let mut failed = None;
+	for &pid in build.pids.iter().filter(|&&pid| still_running(host, procs, pid)) {
+		for tid in procfs::threads(&host.proc_root, pid) {
+			match set_priority(tid, priority) {
+				Ok(()) | Err(Errno::ESRCH) => {}
+				Err(err) => {
+					failed.get_or_insert(format!("pid {}: {}", pid, describe(err)));
+				}
+			}
+		}
+	}
+	failed.map_or(Ok(()), Err)
+}
+
+// from linux/ioprio.h
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+const IOPRIO_CLASS_RT: libc::c_int = 1;
+const IOPRIO_CLASS_BE: libc::c_int = 2;
+const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+
+/// The priority of a build's builder, which the rest of the build inherited unless it changed its own
+///
+/// That's whatever the daemon was started with, like NixOS's `nix.daemonCPUSchedPolicy` and `nix.daemonIOSchedClass`,
+/// until it's changed from here.
+pub fn build_priority(build: &Build) -> Result<Priority, String> {
+	let builder = build.id.builder;
+	get_priority(builder).map_err(|err| format!("pid {}: {}", builder, describe(err)))
+}
+
+fn get_priority(pid: i32) -> Result<Priority, Errno> {
+	// -1 is a valid nice value, so only errno tells a failure apart
+	Errno::clear();
+	// SAFETY: neither call takes pointers
+	let nice = unsafe { libc::getpriority(libc::PRIO_PROCESS, pid as libc::id_t) };
+	if nice == -1 && Errno::last() != Errno::UnknownErrno {
+		return Err(Errno::last());
+	}
+	let ioprio = Errno::result(unsafe { libc::syscall(libc::SYS_ioprio_get, IOPRIO_WHO_PROCESS, pid) })? as libc::c_int;
+	let level = (ioprio & 7) as u8;
+	let io_class = match ioprio >> IOPRIO_CLASS_SHIFT {
+		IOPRIO_CLASS_RT => IoClass::RealTime(level),
+		IOPRIO_CLASS_BE => IoClass::BestEffort(level),
+		IOPRIO_CLASS_IDLE => IoClass::Idle,
+		_ => IoClass::None,
+	};
+	Ok(Priority { nice, io_class })
+}
+
+fn set_priority(tid: i32, priority: Priority) -> Result<(), Errno> {
+	let ioprio = match priority.io_class {
+		IoClass::None => 0,
+		IoClass::RealTime(level) => (IOPRIO_CLASS_RT << IOPRIO_CLASS_SHIFT) | libc::c_int::from(level.min(7)),
+		IoClass::BestEffort(level) => (IOPRIO_CLASS_BE << IOPRIO_CLASS_SHIFT) | libc::c_int::from(level.min(7)),
+		IoClass::Idle => IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+	};
+
+	// SAFETY: neither call takes pointers
+	Errno::result(unsafe { libc::setpriority(libc::PRIO_PROCESS, tid as libc::id_t, priority.nice) })?;
+	Errno::result(unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, tid, ioprio) })?;
+	Ok(())
+}
+
+/// An errno for the status line, suggesting root when that's what it would take
+fn describe(err: Errno) -> String {
+	if matches!(err, Errno::EPERM | Errno::EACCES) && !unistd::geteuid().is_root() {
+		format!("{}, try again as root", err.desc())
+	} else {
+		err.desc().to_string()
+	}
+}
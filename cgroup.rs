@@ -0,0 +1,125 @@
+use crate::host::Host;
+use crate::procfs::ProcTable;
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A `nix-build-uid-N` cgroup that nix created for one build when `use-cgroups` is enabled
+#[derive(Debug)]
+pub struct BuildCgroup {
+	pub path: PathBuf,
+	/// uid the build was started as, taken from the cgroup name
+	pub uid: u32,
+	/// Every process in the cgroup and its descendants, regardless of which uid it runs as
+	pub pids: Vec<i32>,
+}
+
+/// Resource usage nix's build cgroup tracks for the whole build
+#[derive(Debug, Default)]
+pub struct CgroupStats {
+	pub memory_current: Option<u64>,
+	pub cpu_usage_usec: Option<u64>,
+	pub io_read_bytes: Option<u64>,
+	pub io_write_bytes: Option<u64>,
+}
+
+impl BuildCgroup {
+	pub fn stats(&self) -> CgroupStats {
+		let read = |file: &str| fs::read_to_string(self.path.join(file)).ok();
+
+		let memory_current = read("memory.current").and_then(|memory| memory.trim().parse().ok());
+		let cpu_usage_usec = read("cpu.stat").and_then(|stat| {
+			stat.lines()
+				.find_map(|line| line.strip_prefix("usage_usec "))
+				.and_then(|usec| usec.trim().parse().ok())
+		});
+		// io.stat has one line per device: "8:0 rbytes=1 wbytes=2 rios=3 ..."
+		let io_stat = read("io.stat");
+		let io_total = |key: &str| {
+			io_stat.as_ref().map(|stat| {
+				stat.split_whitespace()
+					.filter_map(|field| field.strip_prefix(key)?.parse::<u64>().ok())
+					.sum()
+			})
+		};
+
+		CgroupStats {
+			memory_current,
+			cpu_usage_usec,
+			io_read_bytes: io_total("rbytes="),
+			io_write_bytes: io_total("wbytes="),
+		}
+	}
+}
+
+/// Finds nix's per-build cgroups next to the cgroups the nix-daemon processes run in
+///
+/// nix creates them under the daemon's own cgroup, or its parent once the daemon has moved itself into a sub-cgroup.
+pub fn find_build_cgroups(host: &Host, procs: &ProcTable) -> Vec<BuildCgroup> {
+	let Some(cgroup_fs) = cgroup2_mount(host) else {
+		return Vec::new();
+	};
+	let daemon_cgroups: BTreeSet<PathBuf> = procs
+		.iter()
+		.filter(|process| process.comm == "nix-daemon")
+		.filter_map(|process| process_cgroup(&host.proc_root, &cgroup_fs, process.pid))
+		.flat_map(|cgroup| {
+			let parent = cgroup.parent().map(Path::to_path_buf);
+			std::iter::once(cgroup).chain(parent)
+		})
+		.collect();
+
+	let build_dirs: BTreeSet<(PathBuf, u32)> = daemon_cgroups
+		.iter()
+		.filter_map(|cgroup| fs::read_dir(cgroup).ok())
+		.flatten()
+		.filter_map(|entry| {
+			let entry = entry.ok()?;
+			let uid = entry.file_name().to_str()?.strip_prefix("nix-build-uid-")?.parse().ok()?;
+			Some((entry.path(), uid))
+		})
+		.collect();
+
+	build_dirs
+		.into_iter()
+		.map(|(path, uid)| {
+			let mut pids = Vec::new();
+			collect_pids(&path, &mut pids);
+			pids.sort_unstable();
+			BuildCgroup { path, uid, pids }
+		})
+		.filter(|cgroup| !cgroup.pids.is_empty())
+		.collect()
+}
+
+/// Where the cgroup v2 hierarchy is mounted, /sys/fs/cgroup or /sys/fs/cgroup/unified on hybrid systems
+fn cgroup2_mount(host: &Host) -> Option<PathBuf> {
+	fs::read_to_string(host.proc_root.join("self/mounts"))
+		.ok()?
+		.lines()
+		.find_map(|line| {
+			let fields: Vec<&str> = line.split_whitespace().collect();
+			match fields.as_slice() {
+				[_, mount_point, "cgroup2", ..] => Some(host.path(mount_point)),
+				_ => None,
+			}
+		})
+}
+
+/// Path of a process's cgroup in the unified hierarchy
+fn process_cgroup(proc_root: &Path, cgroup_fs: &Path, pid: i32) -> Option<PathBuf> {
+	let cgroups = fs::read_to_string(proc_root.join(pid.to_string()).join("cgroup")).ok()?;
+	let path = cgroups.lines().find_map(|line| line.strip_prefix("0::"))?;
+	Some(cgroup_fs.join(path.trim_start_matches('/')))
+}
+
+fn collect_pids(cgroup: &Path, pids: &mut Vec<i32>) {
+	if let Ok(procs) = fs::read_to_string(cgroup.join("cgroup.procs")) {
+		pids.extend(procs.lines().filter_map(|pid| pid.trim().parse::<i32>().ok()));
+	}
+	for entry in fs::read_dir(cgroup).into_iter().flatten().flatten() {
+		if entry.file_type().map(|file_type| file_type.is_dir()).unwrap_or(false) {
+			collect_pids(&entry.path(), pids);
+		}
+	}
+}
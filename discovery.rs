@@ -0,0 +1,395 @@
+use crate::cgroup::BuildCgroup;
+use crate::host::Host;
+use crate::nixconf::NixConfig;
+use crate::procfs::{self, ProcTable, Process};
+use crate::BuildId;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// A build as discovery found it, before its processes are measured
+pub(crate) struct FoundBuild {
+	pub outputs: Result<Outputs, BuildError>,
+	pub id: BuildId,
+	/// In ascending pid order
+	pub pids: Vec<i32>,
+}
+
+/// Running builds keyed by build user
+pub(crate) fn get_processes(host: &Host, procs: &ProcTable, cgroups: &[BuildCgroup], config: &NixConfig) -> HashMap<String, FoundBuild> {
+	let mut processes = HashMap::new();
+	let build_uids: HashMap<u32, String> = build_users(host, &config.build_users_group)
+		.into_iter()
+		.map(|user| (user.uid, user.name))
+		.collect();
+	// a build cgroup is named after its uid, which vouches for a slot even if nix.conf doesn't enable auto-allocate-uids
+	let cgroup_slots: HashSet<u32> = cgroups.iter().filter_map(|cgroup| uid_slot(cgroup.uid, config)).collect();
+	let build_user = |uid: u32| {
+		build_uids.get(&uid).cloned().or_else(|| {
+			let slot = uid_slot(uid, config).filter(|slot| config.auto_allocate_uids || cgroup_slots.contains(slot))?;
+			Some(format!("slot-{}", slot))
+		})
+	};
+
+	// A build cgroup holds every process of the build, even ones that switched to another uid,
+	// so it takes precedence over matching processes by uid
+	let mut user_pid_map: HashMap<String, Vec<i32>> = HashMap::new();
+	for cgroup in cgroups {
+		let user = build_user(cgroup.uid).unwrap_or_else(|| format!("uid-{}", cgroup.uid));
+		let pids = cgroup.pids.iter().copied().filter(|&pid| procs.get(pid).is_some());
+		user_pid_map.entry(user).or_default().extend(pids);
+	}
+	let in_cgroup: HashSet<i32> = cgroups.iter().flat_map(|cgroup| cgroup.pids.iter().copied()).collect();
+	for process in procs.iter().filter(|process| !in_cgroup.contains(&process.pid)) {
+		if let Some(user) = build_user(process.uid) {
+			user_pid_map.entry(user).or_default().push(process.pid);
+		}
+	}
+
+	// Without build users nix forks builders as whoever invoked it
+	if config.build_users_group.is_empty() || host.users.group_members(&config.build_users_group).is_none() {
+		let claimed: HashSet<i32> = user_pid_map.values().flatten().copied().collect();
+		for (builder, pids) in client_builds(host, procs, &claimed) {
+			let name = host.users.user_name(builder.uid).unwrap_or_else(|| builder.uid.to_string());
+			user_pid_map.insert(format!("{}:{}", name, builder.pid), pids);
+		}
+	}
+
+	for (user, mut pids) in user_pid_map {
+		pids.sort_unstable();
+		if let Some(process) = builder(procs, &pids) {
+			let id = BuildId {
+				builder: process.pid,
+				start_ticks: process.start_ticks,
+			};
+			let outputs = get_outputs(host, procs, process.uid, process.pid, config);
+			processes.insert(user, FoundBuild { outputs, id, pids });
+		}
+	}
+
+	processes
+}
+
+/// The process nix started a build with: the first one started whose parent isn't part of the build
+///
+/// Not the lowest pid, which a child of the builder gets once the pid counter wraps around.
+fn builder<'a>(procs: &'a ProcTable, pids: &[i32]) -> Option<&'a Process> {
+	let in_build: HashSet<i32> = pids.iter().copied().collect();
+	pids.iter()
+		.filter_map(|&pid| procs.get(pid))
+		.filter(|process| !in_build.contains(&process.ppid))
+		.min_by_key(|process| (process.start_ticks, process.pid))
+}
+
+/// Client commands that build locally on single-user installs
+const NIX_CLIENTS: [&str; 3] = ["nix", "nix-build", "nix-store"];
+
+/// Finds builders started by nix client processes, returning each builder with the pids of its subtree
+///
+/// nix forks a builder as its own child, in the sandbox too, and sets NIX_BUILD_TOP for it.
+/// Processes further down with outputs in their environment are things like the commands run in `nix develop`.
+fn client_builds<'a>(host: &Host, procs: &'a ProcTable, claimed: &HashSet<i32>) -> Vec<(&'a Process, Vec<i32>)> {
+	procs
+		.iter()
+		.filter(|process| NIX_CLIENTS.contains(&process.comm.as_str()))
+		.flat_map(|client| procs.children(client.pid))
+		.filter(|pid| !claimed.contains(pid))
+		.filter_map(|&pid| procs.get(pid))
+		.filter(|process| {
+			procfs::environ(&host.proc_root, process.pid)
+				.is_ok_and(|env| env.contains_key("NIX_BUILD_TOP") && Outputs::from_env(&env).is_some())
+		})
+		.map(|process| (process, procs.subtree(process.pid)))
+		.collect()
+}
+
+/// A member of nix's build users group
+#[derive(Debug, Clone)]
+pub struct BuildUser {
+	pub name: String,
+	pub uid: u32,
+}
+
+/// Members of the build users group, empty if it doesn't exist
+pub fn build_users(host: &Host, group: &str) -> Vec<BuildUser> {
+	host.users.group_members(group).unwrap_or_default()
+}
+
+/// Each auto-allocated build gets a slot of this many uids, even when it only uses the first
+pub const AUTO_UIDS_PER_SLOT: u32 = 1 << 16;
+
+/// Labels a uid from the `auto-allocate-uids` range by its slot, matching nix's userpool2/slot-N locks
+///
+/// Only when nix.conf enables auto-allocate-uids: the range is also where systemd-nspawn picks uids for containers.
+pub fn auto_uid_slot(uid: u32, config: &NixConfig) -> Option<String> {
+	let slot = uid_slot(uid, config).filter(|_| config.auto_allocate_uids)?;
+	Some(format!("slot-{}", slot))
+}
+
+/// Which slot of the auto-allocated range a uid is in, whether or not nix allocates from it
+fn uid_slot(uid: u32, config: &NixConfig) -> Option<u32> {
+	let offset = uid.checked_sub(config.start_id).filter(|&offset| offset < config.id_count)?;
+	Some(offset / AUTO_UIDS_PER_SLOT)
+}
+
+/// What a build produces, as far as its builder's environment tells us
+#[derive(Debug, Clone)]
+pub struct Outputs {
+	/// The derivation's `name`, e.g. hello-2.12
+	pub name: Option<String>,
+	pub pname: Option<String>,
+	pub version: Option<String>,
+	/// Output names and store paths in the order of the derivation's `outputs`
+	pub paths: Vec<(String, String)>,
+	/// Where env-vars was read from, when the builder's environment wasn't readable
+	pub build_dir: Option<PathBuf>,
+}
+
+impl Outputs {
+	fn from_env(env: &HashMap<String, String>) -> Option<Outputs> {
+		let names = env.get("outputs").map(String::as_str).unwrap_or("out");
+		let paths: Vec<(String, String)> = names
+			.split_whitespace()
+			.filter_map(|output| {
+				let path = env.get(output).filter(|path| !path.is_empty())?;
+				Some((output.to_string(), path.clone()))
+			})
+			.collect();
+		if paths.is_empty() {
+			return None;
+		}
+
+		let var = |name: &str| env.get(name).filter(|value| !value.is_empty()).cloned();
+		Some(Outputs {
+			name: var("name"),
+			pname: var("pname"),
+			version: var("version"),
+			paths,
+			build_dir: None,
+		})
+	}
+
+	/// Readable name like `hello 2.12 abc1234`, or with `full_paths` the first output's store path
+	///
+	/// Falls back to the first output path when the builder didn't set a name.
+	pub fn label(&self, full_paths: bool, hash_len: usize) -> String {
+		let first_path = self.paths.first().map(|(_, path)| path.as_str());
+		let readable = match (&self.pname, &self.version) {
+			(Some(pname), Some(version)) => Some(format!("{} {}", pname, version)),
+			_ => self.name.clone(),
+		};
+
+		match (first_path, readable) {
+			(Some(path), _) if full_paths => path.to_string(),
+			(Some(path), Some(readable)) => match store_path_hash(path) {
+				Some(hash) if hash_len > 0 => format!("{} {}", readable, hash.get(..hash_len).unwrap_or(hash)),
+				_ => readable,
+			},
+			(Some(path), None) => short_store_path(path, hash_len),
+			(None, Some(readable)) => readable,
+			(None, None) => "(unknown)".to_string(),
+		}
+	}
+}
+
+/// The hash part of a store path's name, e.g. `abc…xyz` for /nix/store/abc…xyz-hello-2.12
+fn store_path_hash(path: &str) -> Option<&str> {
+	let (hash, _) = Path::new(path).file_name()?.to_str()?.split_once('-')?;
+	Some(hash)
+}
+
+/// Strips the store dir and shortens the hash to `hash_len` characters, dropping it entirely at 0
+pub fn short_store_path(path: &str, hash_len: usize) -> String {
+	let Some(file_name) = Path::new(path).file_name().and_then(|name| name.to_str()) else {
+		return path.to_string();
+	};
+	match file_name.split_once('-') {
+		Some((_, name)) if hash_len == 0 => name.to_string(),
+		Some((hash, name)) => format!("{}-{}", hash.get(..hash_len).unwrap_or(hash), name),
+		None => file_name.to_string(),
+	}
+}
+
+/// Why we couldn't tell what a build is producing
+#[derive(Debug)]
+pub enum BuildError {
+	/// Neither the builder's environment nor its build dir were readable by us
+	PermissionDenied,
+	/// The build's process exited between scanning /proc and reading its environment
+	ProcessVanished,
+	/// Nothing we could read names the build's outputs
+	NoOutPath,
+	UnreadableBuildDir(PathBuf, io::Error),
+}
+
+impl std::fmt::Display for BuildError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			BuildError::PermissionDenied => write!(f, "permission denied, try running as root"),
+			BuildError::ProcessVanished => write!(f, "process vanished"),
+			BuildError::NoOutPath => write!(f, "no out path"),
+			BuildError::UnreadableBuildDir(dir, err) => write!(f, "can't read {}: {}", dir.display(), err),
+		}
+	}
+}
+
+impl std::error::Error for BuildError {}
+
+fn get_outputs(host: &Host, procs: &ProcTable, uid: u32, pid: i32, config: &NixConfig) -> Result<Outputs, BuildError> {
+	// Try to get outputs from /proc environment first
+	let environ_err = match procfs::environ(&host.proc_root, pid) {
+		Ok(env) => match Outputs::from_env(&env) {
+			Some(outputs) => return Ok(outputs),
+			None => None,
+		},
+		Err(err) if err.kind() == io::ErrorKind::NotFound => return Err(BuildError::ProcessVanished),
+		Err(err) => Some(err),
+	};
+
+	let Ok(build_dir) = get_build_dir(host, procs, uid, pid, config) else {
+		return Err(match environ_err {
+			Some(err) if err.kind() == io::ErrorKind::PermissionDenied => BuildError::PermissionDenied,
+			_ => BuildError::NoOutPath,
+		});
+	};
+	let env = get_env_vars(&build_dir).map_err(|err| match err.kind() {
+		// stdenv only writes env-vars once the builder's setup has run
+		io::ErrorKind::NotFound => BuildError::NoOutPath,
+		io::ErrorKind::PermissionDenied if environ_err.is_some() => BuildError::PermissionDenied,
+		_ => BuildError::UnreadableBuildDir(build_dir.clone(), err),
+	})?;
+	let mut outputs = Outputs::from_env(&env).ok_or(BuildError::NoOutPath)?;
+	outputs.build_dir = Some(build_dir);
+	Ok(outputs)
+}
+
+/// Where a build's env-vars lives, from the builder's cwd or the newest `nix-build-*` dir owned by its uid
+pub fn get_build_dir(host: &Host, procs: &ProcTable, uid: u32, pid: i32, config: &NixConfig) -> io::Result<PathBuf> {
+	// The builder starts in its build dir, and /proc/<pid>/cwd reaches it even from outside the sandbox
+	let cwd = host.proc_root.join(pid.to_string()).join("cwd");
+	// the link's target is where it is on the host, and the only way to follow it in a captured tree
+	if let Some(dir) = fs::read_link(&cwd)
+		.ok()
+		.map(|dir| host.path(dir))
+		.filter(|dir| dir.join("env-vars").is_file())
+	{
+		return Ok(dir);
+	}
+	// Inside the sandbox the dir is mounted at /build, which only means something through the magic link
+	if cwd.join("env-vars").is_file() {
+		return Ok(cwd);
+	}
+	if !host.has_owners() {
+		return Err(io::Error::new(
+			io::ErrorKind::NotFound,
+			"build dirs of a captured tree are only found through the builder's cwd",
+		));
+	}
+
+	let roots = config
+		.build_dir
+		.iter()
+		.cloned()
+		.chain([nix_tmpdir(host, procs, pid), PathBuf::from("/nix/var/nix/builds")])
+		.map(|dir| host.path(dir));
+
+	roots
+		.filter_map(|root| fs::read_dir(root).ok())
+		.flatten()
+		.filter_map(|entry| {
+			let entry = entry.ok()?;
+			if !entry.file_name().to_str()?.starts_with("nix-build-") {
+				return None;
+			}
+			// Newer nix keeps a root-owned top dir and hands the builder its `build` subdir
+			[entry.path().join("build"), entry.path()]
+				.into_iter()
+				.find_map(|dir| owned_dir_ctime(&dir, uid).map(|ctime| (ctime, dir)))
+		})
+		.max()
+		.map(|(_, dir)| dir)
+		.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no build dir owned by uid {}", uid)))
+}
+
+/// TMPDIR of the nix process that started a builder, where it puts build dirs unless `build-dir` says otherwise
+///
+/// That's the daemon, or the client on single-user installs; our own TMPDIR says nothing about theirs.
+fn nix_tmpdir(host: &Host, procs: &ProcTable, pid: i32) -> PathBuf {
+	let is_nix = |process: &Process| process.comm == "nix-daemon" || NIX_CLIENTS.contains(&process.comm.as_str());
+	let mut ancestors = std::iter::successors(procs.get(pid), |process| procs.get(process.ppid));
+	ancestors
+		.find(|process| is_nix(process))
+		.or_else(|| procs.iter().find(|process| process.comm == "nix-daemon"))
+		.and_then(|nix| procfs::environ(&host.proc_root, nix.pid).ok()?.remove("TMPDIR"))
+		.map_or_else(|| PathBuf::from("/tmp"), PathBuf::from)
+}
+
+fn owned_dir_ctime(dir: &Path, uid: u32) -> Option<i64> {
+	let metadata = fs::metadata(dir).ok()?;
+	(metadata.is_dir() && metadata.uid() == uid).then(|| metadata.ctime())
+}
+
+/// Reads the variables nix exported to the builder from the env-vars file it leaves in the build dir
+fn get_env_vars(build_dir: &Path) -> io::Result<HashMap<String, String>> {
+	let env_vars = fs::read_to_string(build_dir.join("env-vars"))?;
+	Ok(env_vars
+		.lines()
+		.filter_map(|line| line.strip_prefix("declare -x ")?.split_once('='))
+		.filter_map(|(name, value)| Some((name.to_string(), value.split('"').nth(1)?.to_string())))
+		.collect())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn outputs(vars: &[(&str, &str)]) -> Outputs {
+		let env = vars.iter().map(|&(name, value)| (name.to_string(), value.to_string())).collect();
+		Outputs::from_env(&env).expect("has outputs")
+	}
+
+	#[test]
+	fn labels() {
+		let hello = outputs(&[
+			("pname", "hello"),
+			("version", "2.12"),
+			("name", "hello-2.12"),
+			("out", "/nix/store/0123456789abcdfghijklmnpqrsvwxyz-hello-2.12"),
+		]);
+		assert_eq!(hello.label(false, 7), "hello 2.12 0123456");
+		assert_eq!(hello.label(false, 0), "hello 2.12");
+		assert_eq!(hello.label(true, 7), "/nix/store/0123456789abcdfghijklmnpqrsvwxyz-hello-2.12");
+
+		let named = outputs(&[("name", "source"), ("out", "/nix/store/0123456789abcdfghijklmnpqrsvwxyz-source")]);
+		assert_eq!(named.label(false, 4), "source 0123");
+
+		let unnamed = outputs(&[
+			("outputs", "bin lib"),
+			("lib", "/nix/store/0123456789abcdfghijklmnpqrsvwxyz-thing-lib"),
+		]);
+		assert_eq!(unnamed.label(false, 3), "012-thing-lib");
+	}
+
+	#[test]
+	fn auto_uid_slots_only_when_enabled() {
+		let mut config = NixConfig::default();
+		let uid = config.start_id + 2 * AUTO_UIDS_PER_SLOT + 1;
+		assert_eq!(auto_uid_slot(uid, &config), None);
+
+		config.auto_allocate_uids = true;
+		assert_eq!(auto_uid_slot(uid, &config).as_deref(), Some("slot-2"));
+		assert_eq!(auto_uid_slot(config.start_id - 1, &config), None);
+		assert_eq!(auto_uid_slot(config.start_id + config.id_count, &config), None);
+	}
+
+	#[test]
+	fn short_store_paths() {
+		let path = "/nix/store/0123456789abcdfghijklmnpqrsvwxyz-hello-2.12.drv";
+		assert_eq!(short_store_path(path, 7), "0123456-hello-2.12.drv");
+		assert_eq!(short_store_path(path, 0), "hello-2.12.drv");
+		assert_eq!(short_store_path(path, 64), "0123456789abcdfghijklmnpqrsvwxyz-hello-2.12.drv");
+		assert_eq!(short_store_path("/nix/store/nohash", 7), "nohash");
+	}
+}
@@ -0,0 +1,138 @@
+use crate::json::JsonOutput;
+use nix_scope::Build;
+use serde::Serialize;
+
+/// What happened to a build
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+	BuildStarted,
+	BuildFinished,
+}
+
+/// A build appearing or going away between two snapshots, as printed by `--events`
+#[derive(Serialize)]
+pub struct BuildEvent {
+	pub event: EventKind,
+	/// As in [`nix_scope::Build::user`]
+	pub user: String,
+	pub name: Option<String>,
+	/// In the derivation's order, so the first one is the primary output
+	pub outputs: Vec<JsonOutput>,
+	/// Seconds since the unix epoch
+	pub start_time: Option<u64>,
+	/// Seconds since the unix epoch, when the build was first seen gone
+	pub end_time: Option<u64>,
+	pub duration_seconds: Option<u64>,
+}
+
+/// Builds of the `current` snapshot that weren't in the `previous` one and vice versa
+///
+/// A build user running a different builder counts as one build finishing and another starting.
+/// Builds already running in the first snapshot are reported as started.
+pub fn build_events(previous: Option<&[Build]>, current: &[Build]) -> Vec<BuildEvent> {
+	let is_running = |builds: &[Build], build: &Build| builds.iter().any(|other| other.id == build.id);
+	let mut events = Vec::new();
+
+	if let Some(previous) = previous {
+		let now = crate::unix_now();
+		for build in previous.iter().filter(|build| !is_running(current, build)) {
+			let mut event = BuildEvent::new(EventKind::BuildFinished, build);
+			event.end_time = Some(now);
+			event.duration_seconds = event.start_time.map(|start| now.saturating_sub(start));
+			events.push(event);
+		}
+	}
+	events.extend(
+		current
+			.iter()
+			.filter(|build| !previous.is_some_and(|previous| is_running(previous, build)))
+			.map(|build| BuildEvent::new(EventKind::BuildStarted, build)),
+	);
+
+	events
+}
+
+impl BuildEvent {
+	/// Describes a build as it was last seen
+	fn new(event: EventKind, build: &Build) -> BuildEvent {
+		let outputs = build.outputs.as_ref().ok();
+		BuildEvent {
+			event,
+			user: build.user.clone(),
+			name: outputs.and_then(|outputs| outputs.name.clone()),
+			outputs: JsonOutput::list(outputs),
+			start_time: build.start_time,
+			end_time: None,
+			duration_seconds: None,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use nix_scope::procfs::IoCounters;
+	use nix_scope::{BuildError, BuildId};
+
+	fn build(user: &str, builder: i32, start_ticks: u64, pids: &[i32]) -> Build {
+		Build {
+			user: user.to_string(),
+			id: BuildId { builder, start_ticks },
+			uid: 30001,
+			outputs: Err(BuildError::NoOutPath),
+			pids: pids.to_vec(),
+			processes: Vec::new(),
+			start_time: Some(1_700_000_000),
+			cpu_percent: None,
+			cpu_seconds: 0.0,
+			rss_bytes: 0,
+			pss_bytes: None,
+			swap_bytes: None,
+			io_total: IoCounters::default(),
+			io_rates: None,
+			first_seen: 0,
+		}
+	}
+
+	fn kinds(events: &[BuildEvent]) -> Vec<(EventKind, &str)> {
+		events.iter().map(|event| (event.event, event.user.as_str())).collect()
+	}
+
+	#[test]
+	fn started() {
+		let first = [build("nixbld1", 200, 5000, &[200])];
+		assert_eq!(kinds(&build_events(None, &first)), [(EventKind::BuildStarted, "nixbld1")]);
+
+		let second = [build("nixbld1", 200, 5000, &[200, 201]), build("nixbld2", 300, 6000, &[300])];
+		assert_eq!(kinds(&build_events(Some(&first), &second)), [(EventKind::BuildStarted, "nixbld2")]);
+	}
+
+	#[test]
+	fn finished() {
+		let previous = [build("nixbld1", 200, 5000, &[200]), build("nixbld2", 300, 6000, &[300])];
+		let current = [build("nixbld2", 300, 6000, &[300])];
+		let events = build_events(Some(&previous), &current);
+		assert_eq!(kinds(&events), [(EventKind::BuildFinished, "nixbld1")]);
+		assert!(events[0].end_time.is_some());
+		assert!(events[0].duration_seconds.is_some());
+	}
+
+	#[test]
+	fn builder_replaced_under_same_user() {
+		let previous = [build("nixbld1", 200, 5000, &[200])];
+		// the pid was reused by the next build's builder
+		let current = [build("nixbld1", 200, 9000, &[200])];
+		assert_eq!(
+			kinds(&build_events(Some(&previous), &current)),
+			[(EventKind::BuildFinished, "nixbld1"), (EventKind::BuildStarted, "nixbld1")]
+		);
+	}
+
+	#[test]
+	fn lower_pid_joining_is_the_same_build() {
+		let previous = [build("nixbld1", 200, 5000, &[200, 201])];
+		let current = [build("nixbld1", 200, 5000, &[150, 200, 201])];
+		assert!(build_events(Some(&previous), &current).is_empty());
+	}
+}
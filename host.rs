@@ -0,0 +1,131 @@
+use crate::discovery::BuildUser;
+use std::fs;
+use std::path::{Path, PathBuf};
+use users::os::unix::GroupExt;
+
+/// Where discovery reads the system from, so a captured or synthetic tree can stand in for the live one
+pub struct Host {
+	/// Usually /proc
+	pub proc_root: PathBuf,
+	/// Prefix for every other absolute path read, like build dirs, the cgroup hierarchy and /etc/nix; usually /
+	pub fs_root: PathBuf,
+	pub users: Box<dyn UserDb>,
+}
+
+impl Host {
+	/// The machine we're running on
+	pub fn live() -> Host {
+		Host {
+			proc_root: PathBuf::from("/proc"),
+			fs_root: PathBuf::from("/"),
+			users: Box::new(SystemUsers),
+		}
+	}
+
+	/// Reads `proc_root` for processes and everything else below `fs_root`, including users and groups from its /etc
+	///
+	/// File owners below `fs_root` are taken to be whoever copied the tree, so build dirs are only found through builders' cwd.
+	pub fn rooted(proc_root: PathBuf, fs_root: PathBuf) -> Host {
+		Host {
+			proc_root,
+			users: Box::new(PasswdFiles { etc: fs_root.join("etc") }),
+			fs_root,
+		}
+	}
+
+	/// Whether the pids read are processes of the machine we're running on, which can be signalled
+	pub fn is_local(&self) -> bool {
+		self.proc_root == Path::new("/proc")
+	}
+
+	/// Whether files keep the owners the host gave them, which tells build dirs of different build users apart
+	pub fn has_owners(&self) -> bool {
+		self.fs_root == Path::new("/")
+	}
+
+	/// `path` below the filesystem root, for absolute paths as the host itself would see them
+	pub fn path(&self, path: impl AsRef<Path>) -> PathBuf {
+		self.fs_root.join(path.as_ref().strip_prefix("/").unwrap_or(path.as_ref()))
+	}
+}
+
+/// Where user and group names come from
+pub trait UserDb {
+	/// Members of a group, or None if it doesn't exist
+	fn group_members(&self, group: &str) -> Option<Vec<BuildUser>>;
+	fn user_name(&self, uid: u32) -> Option<String>;
+}
+
+/// The system's user database through NSS, as `id` and `getent` see it
+pub struct SystemUsers;
+
+impl UserDb for SystemUsers {
+	fn group_members(&self, group: &str) -> Option<Vec<BuildUser>> {
+		let group = users::get_group_by_name(group)?;
+		Some(
+			group
+				.members()
+				.iter()
+				.filter_map(|name| {
+					let user = users::get_user_by_name(name)?;
+					Some(BuildUser {
+						name: name.to_string_lossy().into_owned(),
+						uid: user.uid(),
+					})
+				})
+				.collect(),
+		)
+	}
+
+	fn user_name(&self, uid: u32) -> Option<String> {
+		users::get_user_by_uid(uid).map(|user| user.name().to_string_lossy().into_owned())
+	}
+}
+
+/// passwd and group files in a directory, like a copy of another machine's /etc
+pub struct PasswdFiles {
+	pub etc: PathBuf,
+}
+
+impl PasswdFiles {
+	/// Colon-separated fields of each line of `etc/<file>`
+	fn entries(&self, file: &str) -> Vec<Vec<String>> {
+		fs::read_to_string(self.etc.join(file))
+			.unwrap_or_default()
+			.lines()
+			.filter(|line| !line.starts_with('#'))
+			.map(|line| line.split(':').map(String::from).collect())
+			.collect()
+	}
+}
+
+impl UserDb for PasswdFiles {
+	fn group_members(&self, group: &str) -> Option<Vec<BuildUser>> {
+		// group: name:password:gid:member,member
+		let groups = self.entries("group");
+		let members = groups.iter().find(|entry| entry[0] == group)?.get(3).map_or("", String::as_str);
+		let passwd = self.entries("passwd");
+		Some(
+			members
+				.split(',')
+				.filter(|name| !name.is_empty())
+				.filter_map(|name| {
+					// passwd: name:password:uid:...
+					let uid = passwd.iter().find(|entry| entry[0] == name)?.get(2)?.parse().ok()?;
+					Some(BuildUser {
+						name: name.to_string(),
+						uid,
+					})
+				})
+				.collect(),
+		)
+	}
+
+	fn user_name(&self, uid: u32) -> Option<String> {
+		let uid = uid.to_string();
+		self.entries("passwd")
+			.into_iter()
+			.find(|entry| entry.get(2) == Some(&uid))
+			.map(|mut entry| entry.swap_remove(0))
+	}
+}
@@ -0,0 +1,120 @@
+use nix_scope::host::Host;
+use nix_scope::nixconf::NixConfig;
+use nix_scope::store::StoreDb;
+use nix_scope::{Outputs, Snapshot};
+use serde::Serialize;
+use std::path::PathBuf;
+
+/// Everything known about the running builds at one point in time, as printed by `--json`
+#[derive(Serialize)]
+pub struct JsonSnapshot {
+	/// Seconds since the unix epoch
+	pub time: u64,
+	pub builds: Vec<JsonBuild>,
+}
+
+#[derive(Serialize)]
+pub struct JsonBuild {
+	/// As in [`nix_scope::Build::user`]
+	pub user: String,
+	/// uid the builder runs as
+	pub uid: u32,
+	pub pids: Vec<i32>,
+	pub name: Option<String>,
+	/// In the derivation's order, so the first one is the primary output
+	pub outputs: Vec<JsonOutput>,
+	pub drv: Option<String>,
+	pub build_dir: Option<PathBuf>,
+	/// Seconds since the unix epoch
+	pub start_time: Option<u64>,
+	/// Why the outputs couldn't be determined
+	pub error: Option<String>,
+	pub processes: Vec<JsonProcess>,
+}
+
+#[derive(Serialize)]
+pub struct JsonOutput {
+	pub name: String,
+	pub path: String,
+}
+
+impl JsonOutput {
+	/// Every output of a build, none if they aren't known
+	pub fn list(outputs: Option<&Outputs>) -> Vec<JsonOutput> {
+		outputs
+			.iter()
+			.flat_map(|outputs| &outputs.paths)
+			.map(|(name, path)| JsonOutput {
+				name: name.clone(),
+				path: path.clone(),
+			})
+			.collect()
+	}
+}
+
+#[derive(Serialize)]
+pub struct JsonProcess {
+	pub pid: i32,
+	pub ppid: i32,
+	pub uid: u32,
+	pub command: String,
+	/// Seconds since the unix epoch
+	pub start_time: u64,
+	/// Usage since the previous snapshot where 100 is one full core, absent for the first one
+	pub cpu_percent: Option<f64>,
+	pub cpu_seconds: u64,
+	pub rss_bytes: u64,
+	pub pss_bytes: Option<u64>,
+	pub swap_bytes: Option<u64>,
+}
+
+impl JsonSnapshot {
+	pub fn new(host: &Host, config: &NixConfig, store: &StoreDb, snapshot: &Snapshot) -> JsonSnapshot {
+		let builds = snapshot
+			.builds
+			.iter()
+			.map(|build| {
+				let outputs = build.outputs.as_ref().ok();
+				let drv = outputs
+					.and_then(|outputs| outputs.paths.first())
+					.and_then(|(_, path)| store.drv_for_output(path));
+				let build_dir = outputs
+					.and_then(|outputs| outputs.build_dir.clone())
+					.or_else(|| nix_scope::get_build_dir(host, &snapshot.procs, build.uid, build.id.builder, config).ok());
+
+				JsonBuild {
+					user: build.user.clone(),
+					uid: build.uid,
+					pids: build.pids.clone(),
+					name: outputs.and_then(|outputs| outputs.name.clone()),
+					outputs: JsonOutput::list(outputs),
+					drv,
+					build_dir,
+					start_time: build.start_time,
+					error: build.outputs.as_ref().err().map(|err| err.to_string()),
+					processes: build
+						.processes
+						.iter()
+						.map(|process| JsonProcess {
+							pid: process.pid,
+							ppid: process.ppid,
+							uid: process.uid,
+							command: process.command.clone(),
+							start_time: process.start_time,
+							cpu_percent: process.cpu_percent,
+							cpu_seconds: process.cpu_seconds,
+							rss_bytes: process.rss_bytes,
+							pss_bytes: process.pss_bytes,
+							swap_bytes: process.swap_bytes,
+						})
+						.collect(),
+				}
+			})
+			.collect();
+
+		JsonSnapshot {
+			time: crate::unix_now(),
+			builds,
+		}
+	}
+}
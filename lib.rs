@@ -0,0 +1,236 @@
+//! Finds the builds a nix daemon is running and what they're doing, by reading /proc, cgroups and the build dirs
+
+pub mod cgroup;
+mod discovery;
+pub mod host;
+pub mod nixconf;
+pub mod procfs;
+pub mod store;
+
+pub use discovery::{auto_uid_slot, build_users, get_build_dir, short_store_path, BuildError, BuildUser, Outputs, AUTO_UIDS_PER_SLOT};
+
+use cgroup::BuildCgroup;
+use host::Host;
+use nixconf::NixConfig;
+use procfs::{IoCounters, MemInfo, ProcTable};
+use std::collections::HashMap;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::time::Instant;
+
+/// Tells builds apart, including successive builds of one build user and builders whose pid was reused
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BuildId {
+	/// pid of the process nix started the build with
+	pub builder: i32,
+	/// The builder's start time in clock ticks after boot
+	pub start_ticks: u64,
+}
+
+/// A running build, with totals over all of its processes
+#[derive(Debug)]
+pub struct Build {
+	/// Build user, auto-allocated uid slot as `slot-N`, `uid-N` for a build cgroup of an unknown uid,
+	/// or `user:pid` for a builder found under a nix client on single-user installs
+	pub user: String,
+	pub id: BuildId,
+	/// uid the builder runs as
+	pub uid: u32,
+	/// What the build is producing, or why we couldn't tell
+	pub outputs: Result<Outputs, BuildError>,
+	/// Every process of the build in ascending pid order, which says nothing about which one is the builder
+	pub pids: Vec<i32>,
+	/// The build's processes that were still running when /proc was scanned, in the order of `pids`
+	pub processes: Vec<BuildProcess>,
+	/// Seconds since the unix epoch, from the oldest process
+	pub start_time: Option<u64>,
+	/// Usage since the previous snapshot where 100 is one full core, None for the first snapshot
+	///
+	/// Includes processes that exited in between, unlike the sum of the processes' own usage.
+	pub cpu_percent: Option<f64>,
+	/// User and system cpu time of the build so far, from its cgroup if it has one
+	///
+	/// Otherwise it's the running processes and the children they reaped,
+	/// missing only processes that exited without another process of the build waiting for them.
+	pub cpu_seconds: f64,
+	pub rss_bytes: u64,
+	/// Only set when smaps_rollup was readable for at least one of the build's processes
+	pub pss_bytes: Option<u64>,
+	pub swap_bytes: Option<u64>,
+	/// Everything read and written by the build's processes and their reaped children
+	///
+	/// The kernel adds a reaped child's counters to its parent's, so this includes exited children
+	/// as long as whatever waited for them is still part of the build.
+	pub io_total: IoCounters,
+	/// Bytes per second read and written since the previous snapshot, if the same builder was running then
+	pub io_rates: Option<(f64, f64)>,
+	/// Grows with every build seen, so builds can be kept in the order they appeared
+	pub first_seen: u64,
+}
+
+/// One process of a build
+#[derive(Debug, Clone)]
+pub struct BuildProcess {
+	pub pid: i32,
+	pub ppid: i32,
+	pub uid: u32,
+	/// Command line, or `[comm]` when it's empty
+	pub command: String,
+	/// Seconds since the unix epoch
+	pub start_time: u64,
+	pub cpu_seconds: u64,
+	/// Usage since the previous snapshot where 100 is one full core, None for the first snapshot
+	pub cpu_percent: Option<f64>,
+	pub rss_bytes: u64,
+	/// Only set when smaps_rollup was readable
+	pub pss_bytes: Option<u64>,
+	pub swap_bytes: Option<u64>,
+}
+
+/// Everything read from the system in one refresh
+#[derive(Debug)]
+pub struct Snapshot {
+	pub procs: ProcTable,
+	pub cgroups: Vec<BuildCgroup>,
+	/// Sorted by user
+	pub builds: Vec<Build>,
+	pub meminfo: Option<MemInfo>,
+	pub taken_at: Instant,
+	/// Per-pid cpu usage since the previous snapshot, empty for the first one
+	cpu_percent: HashMap<i32, f64>,
+	next_seen: u64,
+}
+
+/// Scans for running builds, comparing against `previous` for cpu and I/O rates
+///
+/// Pass the previous snapshot every time rather than only the first one,
+/// so builds that are still running keep their place and their outputs once the builder stops being readable.
+pub fn snapshot(host: &Host, config: &NixConfig, previous: Option<&Snapshot>) -> Snapshot {
+	let procs = ProcTable::scan(&host.proc_root);
+	let taken_at = Instant::now();
+	let interval = previous.map(|previous| taken_at.duration_since(previous.taken_at));
+	let cpu_percent = previous
+		.zip(interval)
+		.map(|(previous, interval)| procs.cpu_percent_since(&previous.procs, interval))
+		.unwrap_or_default();
+	let cgroups = cgroup::find_build_cgroups(host, &procs);
+
+	let mut found: Vec<_> = discovery::get_processes(host, &procs, &cgroups, config).into_iter().collect();
+	found.sort_by(|(a, _), (b, _)| a.cmp(b));
+	let mut next_seen = previous.map_or(0, |previous| previous.next_seen);
+	let builds = found
+		.into_iter()
+		.map(|(user, discovery::FoundBuild { mut outputs, id, pids })| {
+			// a build user running a different builder is a new build
+			let before = previous.and_then(|previous| previous.build_by_id(id));
+			// an exiting builder's environment reads as empty, so keep what it was building until it's gone
+			if let (Err(_), Some(Ok(before))) = (&outputs, before.map(|before| &before.outputs)) {
+				outputs = Ok(before.clone());
+			}
+
+			let processes: Vec<BuildProcess> = pids
+				.iter()
+				.filter_map(|&pid| procs.get(pid))
+				.map(|process| {
+					let memory = procfs::smaps_rollup(&host.proc_root, process.pid);
+					BuildProcess {
+						pid: process.pid,
+						ppid: process.ppid,
+						uid: process.uid,
+						command: process.command(),
+						start_time: procs.start_time(process),
+						cpu_seconds: procs.cpu_seconds(process),
+						cpu_percent: cpu_percent.get(&process.pid).copied(),
+						rss_bytes: procs.rss_bytes(process),
+						pss_bytes: memory.map(|memory| memory.pss_bytes),
+						swap_bytes: memory.map(|memory| memory.swap_bytes),
+					}
+				})
+				.collect();
+			let mut io_total = IoCounters::default();
+			for io in pids.iter().filter_map(|&pid| procfs::io(&host.proc_root, pid)) {
+				io_total += io;
+			}
+			let io_rates = before.zip(interval).map(|(before, interval)| {
+				let secs = interval.as_secs_f64().max(f64::EPSILON);
+				// counters drop when a process exits without being reaped by another process of the build
+				let read = io_total.read_bytes.saturating_sub(before.io_total.read_bytes) as f64 / secs;
+				let write = io_total.write_bytes.saturating_sub(before.io_total.write_bytes) as f64 / secs;
+				(read, write)
+			});
+			// the build dir's ctime moves whenever an entry is added, so it's only a fallback for when no process was readable
+			let build_dir_ctime = || {
+				let build_dir = outputs.as_ref().ok()?.build_dir.as_ref()?;
+				u64::try_from(fs::metadata(build_dir).ok()?.ctime()).ok()
+			};
+			let start_time = processes.iter().map(|process| process.start_time).min().or_else(build_dir_ctime);
+			let cgroup = cgroups.iter().find(|cgroup| cgroup.pids.contains(&id.builder));
+			let cpu_seconds = cgroup
+				.and_then(|cgroup| cgroup.stats().cpu_usage_usec)
+				.map(|usec| usec as f64 / 1_000_000.0)
+				.unwrap_or_else(|| procs.total_cpu_seconds(pids.iter().filter_map(|&pid| procs.get(pid))));
+			let cpu_percent = match before.zip(interval) {
+				Some((before, interval)) => {
+					let secs = interval.as_secs_f64().max(f64::EPSILON);
+					Some((cpu_seconds - before.cpu_seconds).max(0.0) / secs * 100.0)
+				}
+				// a new build's processes weren't part of it before, so all of their usage counts
+				None => previous.map(|_| processes.iter().filter_map(|process| process.cpu_percent).sum()),
+			};
+			let first_seen = before.map_or_else(
+				|| {
+					next_seen += 1;
+					next_seen
+				},
+				|before| before.first_seen,
+			);
+
+			Build {
+				uid: procs.get(id.builder).map_or(0, |builder| builder.uid),
+				user,
+				id,
+				outputs,
+				start_time,
+				cpu_percent,
+				cpu_seconds,
+				rss_bytes: processes.iter().map(|process| process.rss_bytes).sum(),
+				pss_bytes: processes.iter().filter_map(|process| process.pss_bytes).reduce(|a, b| a + b),
+				swap_bytes: processes.iter().filter_map(|process| process.swap_bytes).reduce(|a, b| a + b),
+				io_total,
+				io_rates,
+				first_seen,
+				pids,
+				processes,
+			}
+		})
+		.collect();
+
+	Snapshot {
+		procs,
+		cgroups,
+		builds,
+		meminfo: procfs::meminfo(&host.proc_root),
+		taken_at,
+		cpu_percent,
+		next_seen,
+	}
+}
+
+impl Snapshot {
+	pub fn build(&self, user: &str) -> Option<&Build> {
+		self.builds.iter().find(|build| build.user == user)
+	}
+
+	/// The same build in this snapshot, if it's still running
+	pub fn build_by_id(&self, id: BuildId) -> Option<&Build> {
+		self.builds.iter().find(|build| build.id == id)
+	}
+
+	/// Summed cpu usage of some processes, if there was a previous snapshot to compare against
+	pub fn cpu_percent(&self, pids: &[i32]) -> Option<f64> {
+		if self.cpu_percent.is_empty() {
+			return None;
+		}
+		Some(pids.iter().filter_map(|pid| self.cpu_percent.get(pid)).sum())
+	}
+}
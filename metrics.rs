@@ -0,0 +1,171 @@
+use crate::events::EventKind;
+use nix_scope::host::Host;
+use nix_scope::nixconf::NixConfig;
+use nix_scope::Snapshot;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+/// Serves Prometheus metrics about the running builds on `addr`
+///
+/// /proc is only scanned when metrics are asked for, and at most once every `delay` however many scrapers there are.
+/// Requests are answered one at a time.
+pub fn serve(addr: &str, host: &Host, config: &NixConfig, delay: Duration) -> io::Result<()> {
+	let listener = TcpListener::bind(addr)?;
+	let mut snapshot = nix_scope::snapshot(host, config, None);
+	let mut completed = 0;
+	let mut rendered = render(host, config, &snapshot, completed);
+
+	for stream in listener.incoming().map_while(Result::ok) {
+		let metrics = || {
+			if snapshot.taken_at.elapsed() >= delay {
+				let next = nix_scope::snapshot(host, config, Some(&snapshot));
+				completed += crate::events::build_events(Some(&snapshot.builds), &next.builds)
+					.iter()
+					.filter(|event| event.event == EventKind::BuildFinished)
+					.count() as u64;
+				snapshot = next;
+				rendered = render(host, config, &snapshot, completed);
+			}
+			rendered.clone()
+		};
+		// a misbehaving client only costs us its own response
+		let _ = respond(stream, metrics);
+	}
+	Ok(())
+}
+
+fn respond(stream: TcpStream, metrics: impl FnOnce() -> String) -> io::Result<()> {
+	stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+	let mut reader = BufReader::new(&stream);
+	let mut request_line = String::new();
+	reader.read_line(&mut request_line)?;
+	// the headers don't change the answer, but have to be read before replying
+	let mut header = String::new();
+	while reader.read_line(&mut header)? > 0 && !header.trim().is_empty() {
+		header.clear();
+	}
+
+	let path = request_line.split_whitespace().nth(1).unwrap_or("/");
+	let (status, body) = match path.split('?').next() {
+		Some("/metrics") => ("200 OK", metrics()),
+		_ => ("404 Not Found", "metrics are served at /metrics\n".to_string()),
+	};
+	let mut stream = &stream;
+	write!(
+		stream,
+		"HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+		status,
+		body.len(),
+		body
+	)?;
+	stream.flush()
+}
+
+/// Formats a snapshot in the Prometheus text exposition format
+fn render(host: &Host, config: &NixConfig, snapshot: &Snapshot, completed: u64) -> String {
+	let build_uids: Vec<u32> = nix_scope::build_users(host, &config.build_users_group)
+		.iter()
+		.map(|user| user.uid)
+		.collect();
+	let users_total = if config.auto_allocate_uids {
+		(config.id_count / nix_scope::AUTO_UIDS_PER_SLOT) as usize
+	} else {
+		build_uids.len()
+	};
+	// builds on single-user installs run as whoever started them rather than a build user
+	let users_in_use = snapshot
+		.builds
+		.iter()
+		.filter(|build| build_uids.contains(&build.uid) || nix_scope::auto_uid_slot(build.uid, config).is_some())
+		.count();
+
+	let now = crate::unix_now();
+	let mut cpu_seconds = String::new();
+	let mut rss_bytes = String::new();
+	let mut elapsed_seconds = String::new();
+	for build in &snapshot.builds {
+		let name = match &build.outputs {
+			Ok(outputs) => outputs.name.clone().unwrap_or_else(|| outputs.label(false, 0)),
+			Err(_) => String::new(),
+		};
+		let labels = format!("name=\"{}\",user=\"{}\"", escape_label(&name), escape_label(&build.user));
+
+		let _ = writeln!(cpu_seconds, "nix_scope_build_cpu_seconds_total{{{}}} {}", labels, build.cpu_seconds);
+		let _ = writeln!(rss_bytes, "nix_scope_build_rss_bytes{{{}}} {}", labels, build.rss_bytes);
+		if let Some(start) = build.start_time {
+			let _ = writeln!(
+				elapsed_seconds,
+				"nix_scope_build_elapsed_seconds{{{}}} {}",
+				labels,
+				now.saturating_sub(start)
+			);
+		}
+	}
+
+	let mut out = String::new();
+	let mut metric = |name: &str, kind: &str, help: &str, samples: String| {
+		let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+		out.push_str(&samples);
+	};
+	metric(
+		"nix_scope_active_builds",
+		"gauge",
+		"Builds currently running",
+		format!("nix_scope_active_builds {}\n", snapshot.builds.len()),
+	);
+	metric(
+		"nix_scope_build_cpu_seconds_total",
+		"counter",
+		"User and system cpu time of a build, including processes that already exited",
+		cpu_seconds,
+	);
+	metric(
+		"nix_scope_build_rss_bytes",
+		"gauge",
+		"Resident memory of a build's processes",
+		rss_bytes,
+	);
+	metric(
+		"nix_scope_build_elapsed_seconds",
+		"gauge",
+		"Wall-clock time since a build's oldest process started",
+		elapsed_seconds,
+	);
+	metric(
+		"nix_scope_build_users_total",
+		"gauge",
+		"Build users or auto-allocated uid slots available to the daemon",
+		format!("nix_scope_build_users_total {}\n", users_total),
+	);
+	metric(
+		"nix_scope_build_users_in_use",
+		"gauge",
+		"Build users or uid slots with a build running",
+		format!("nix_scope_build_users_in_use {}\n", users_in_use),
+	);
+	metric(
+		"nix_scope_builds_completed_total",
+		"counter",
+		"Builds seen finishing since nix-scope started, missing any that started and finished between two scrapes",
+		format!("nix_scope_builds_completed_total {}\n", completed),
+	);
+	out
+}
+
+/// Escapes a label value as the text format requires
+fn escape_label(value: &str) -> String {
+	value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn escapes_label_values() {
+		assert_eq!(escape_label("hello-2.12"), "hello-2.12");
+		assert_eq!(escape_label("a \"quoted\" \\ name\nnext"), "a \\\"quoted\\\" \\\\ name\\nnext");
+	}
+}
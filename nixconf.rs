@@ -0,0 +1,156 @@
+use crate::host::Host;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The subset of the nix daemon's settings that affects how builds are found and displayed
+#[derive(Debug, Clone)]
+pub struct NixConfig {
+	pub build_users_group: String,
+	pub max_jobs: u32,
+	/// 0 means every core
+	pub cores: u32,
+	pub sandbox: String,
+	pub build_dir: Option<PathBuf>,
+	pub auto_allocate_uids: bool,
+	pub start_id: u32,
+	pub id_count: u32,
+}
+
+impl Default for NixConfig {
+	fn default() -> Self {
+		NixConfig {
+			build_users_group: "nixbld".to_string(),
+			max_jobs: 1,
+			cores: 0,
+			sandbox: "true".to_string(),
+			build_dir: None,
+			auto_allocate_uids: false,
+			start_id: 872415232,
+			id_count: 128 << 16,
+		}
+	}
+}
+
+impl NixConfig {
+	/// Reads $NIX_CONF_DIR/nix.conf (default /etc/nix/nix.conf), both taken as paths on the host, falling back to nix's defaults
+	pub fn load(host: &Host) -> NixConfig {
+		let conf_dir = host.path(std::env::var_os("NIX_CONF_DIR").map_or_else(|| PathBuf::from("/etc/nix"), PathBuf::from));
+		let mut settings = HashMap::new();
+		read_settings(host, &conf_dir.join("nix.conf"), &mut settings, 0);
+		NixConfig::from_settings(&settings)
+	}
+
+	fn from_settings(settings: &HashMap<String, String>) -> NixConfig {
+		let mut config = NixConfig::default();
+		let get = |name: &str| settings.get(name).map(String::as_str);
+
+		if let Some(group) = get("build-users-group") {
+			config.build_users_group = group.to_string();
+		}
+		if let Some(max_jobs) = get("max-jobs") {
+			config.max_jobs = if max_jobs == "auto" {
+				std::thread::available_parallelism().map(|n| n.get() as u32).unwrap_or(1)
+			} else {
+				max_jobs.parse().unwrap_or(config.max_jobs)
+			};
+		}
+		if let Some(cores) = get("cores").and_then(|cores| cores.parse().ok()) {
+			config.cores = cores;
+		}
+		if let Some(sandbox) = get("sandbox") {
+			config.sandbox = sandbox.to_string();
+		}
+		config.build_dir = get("build-dir").filter(|dir| !dir.is_empty()).map(PathBuf::from);
+		if let Some(auto_allocate_uids) = get("auto-allocate-uids") {
+			config.auto_allocate_uids = auto_allocate_uids == "true";
+		}
+		if let Some(start_id) = get("start-id").and_then(|id| id.parse().ok()) {
+			config.start_id = start_id;
+		}
+		if let Some(id_count) = get("id-count").and_then(|count| count.parse().ok()) {
+			config.id_count = id_count;
+		}
+
+		config
+	}
+}
+
+/// Parses a nix.conf into `settings`, following `include` and `!include` relative to the including file
+///
+/// `path` is already below the host's root, and absolute includes are taken as paths on the host.
+fn read_settings(host: &Host, path: &Path, settings: &mut HashMap<String, String>, depth: usize) {
+	// guard against include loops rather than recursing forever
+	if depth > 16 {
+		return;
+	}
+	let Ok(contents) = fs::read_to_string(path) else {
+		return;
+	};
+	let base = path.parent().unwrap_or(Path::new("/"));
+
+	for line in contents.lines() {
+		let line = line.split('#').next().unwrap_or("");
+		let tokens: Vec<&str> = line.split_whitespace().collect();
+		match tokens.as_slice() {
+			[] => {}
+			// a missing `include` is an error to nix, but here both just mean "nothing more to read"
+			["include" | "!include", file] => {
+				let file = Path::new(file);
+				let file = if file.is_absolute() { host.path(file) } else { base.join(file) };
+				read_settings(host, &file, settings, depth + 1)
+			}
+			[name, "=", value @ ..] => {
+				settings.insert(name.to_string(), value.join(" "));
+			}
+			_ => {}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn follows_includes() {
+		let dir = std::env::temp_dir().join(format!("nix-scope-nixconf-{}", std::process::id()));
+		fs::create_dir_all(dir.join("conf.d")).unwrap();
+		fs::write(
+			dir.join("nix.conf"),
+			"build-users-group = builders\ninclude conf.d/jobs.conf\n!include missing.conf\nsandbox = relaxed # trailing comment\n",
+		)
+		.unwrap();
+		fs::write(
+			dir.join("conf.d/jobs.conf"),
+			"max-jobs = 8\ncores = 2\nsandbox = false\ninclude ../loop.conf\n",
+		)
+		.unwrap();
+		fs::write(dir.join("loop.conf"), "include loop.conf\n").unwrap();
+
+		let mut settings = HashMap::new();
+		read_settings(&Host::live(), &dir.join("nix.conf"), &mut settings, 0);
+		let config = NixConfig::from_settings(&settings);
+		fs::remove_dir_all(&dir).unwrap();
+
+		assert_eq!(config.build_users_group, "builders");
+		assert_eq!(config.max_jobs, 8);
+		assert_eq!(config.cores, 2);
+		// settings after an include override the included ones
+		assert_eq!(config.sandbox, "relaxed");
+	}
+	#[test]
+	fn absolute_includes_below_the_root() {
+		let root = std::env::temp_dir().join(format!("nix-scope-nixconf-root-{}", std::process::id()));
+		fs::create_dir_all(root.join("etc/nix")).unwrap();
+		fs::write(root.join("etc/nix/nix.conf"), "include /etc/nix/machine.conf\n").unwrap();
+		fs::write(root.join("etc/nix/machine.conf"), "max-jobs = 3\n").unwrap();
+
+		let host = Host::rooted(root.join("proc"), root.clone());
+		let mut settings = HashMap::new();
+		read_settings(&host, &host.path("/etc/nix/nix.conf"), &mut settings, 0);
+		fs::remove_dir_all(&root).unwrap();
+
+		assert_eq!(NixConfig::from_settings(&settings).max_jobs, 3);
+	}
+}
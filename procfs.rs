@@ -0,0 +1,382 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+/// A single process as read from /proc/<pid>
+#[derive(Debug, Clone)]
+pub struct Process {
+	pub pid: i32,
+	pub ppid: i32,
+	/// Effective uid, from the `Uid:` line of /proc/<pid>/status like ps
+	///
+	/// The owner of /proc/<pid> itself is root for non-dumpable processes, and whoever copied it in a captured tree.
+	pub uid: u32,
+	pub comm: String,
+	pub cmdline: String,
+	/// Start time in clock ticks after boot
+	pub start_ticks: u64,
+	pub utime_ticks: u64,
+	pub stime_ticks: u64,
+	/// User and system time of children this process waited for, which the kernel adds when reaping them
+	pub cutime_ticks: u64,
+	pub cstime_ticks: u64,
+	pub rss_pages: u64,
+}
+
+impl Process {
+	fn read(proc_root: &Path, pid: i32) -> Option<Process> {
+		let dir = proc_root.join(pid.to_string());
+		let stat = fs::read_to_string(dir.join("stat")).ok()?;
+		let status = fs::read_to_string(dir.join("status")).ok()?;
+		let cmdline = fs::read(dir.join("cmdline")).unwrap_or_default();
+		Process::parse(pid, &stat, &status, &cmdline)
+	}
+
+	/// Builds a process from the contents of its stat, status and cmdline files
+	fn parse(pid: i32, stat: &str, status: &str, cmdline: &[u8]) -> Option<Process> {
+		// real, effective, saved set and filesystem uid
+		let uid = status
+			.lines()
+			.find_map(|line| line.strip_prefix("Uid:"))?
+			.split_whitespace()
+			.nth(1)?
+			.parse()
+			.ok()?;
+
+		// comm is wrapped in parens and may itself contain spaces or parens
+		let comm_start = stat.find('(')?;
+		// a truncated or garbled stat can have its last ) before the first (
+		let comm_end = stat.rfind(')').filter(|&comm_end| comm_end > comm_start)?;
+		let comm = stat[comm_start + 1..comm_end].to_string();
+		// fields after comm start at field 3 (state)
+		let fields: Vec<&str> = stat[comm_end + 1..].split_whitespace().collect();
+		let field = |n: usize| fields.get(n - 3).and_then(|f| f.parse::<u64>().ok());
+
+		let cmdline = String::from_utf8_lossy(cmdline)
+			.split('\0')
+			.filter(|arg| !arg.is_empty())
+			.collect::<Vec<_>>()
+			.join(" ");
+
+		Some(Process {
+			pid,
+			ppid: field(4)? as i32,
+			uid,
+			comm,
+			cmdline,
+			start_ticks: field(22)?,
+			utime_ticks: field(14)?,
+			stime_ticks: field(15)?,
+			cutime_ticks: field(16)?,
+			cstime_ticks: field(17)?,
+			rss_pages: field(24)?,
+		})
+	}
+
+	/// Command line, or `[comm]` for kernel threads and zombies like ps does
+	pub fn command(&self) -> String {
+		if self.cmdline.is_empty() {
+			format!("[{}]", self.comm)
+		} else {
+			self.cmdline.clone()
+		}
+	}
+
+	pub fn cpu_ticks(&self) -> u64 {
+		self.utime_ticks + self.stime_ticks
+	}
+
+	/// Cpu time of the process and every descendant it has reaped, directly or through its reaped children
+	pub fn cpu_ticks_with_children(&self) -> u64 {
+		self.cpu_ticks() + self.cutime_ticks + self.cstime_ticks
+	}
+}
+
+/// Snapshot of every process visible in a /proc, taken in a single pass
+#[derive(Debug, Default)]
+pub struct ProcTable {
+	processes: BTreeMap<i32, Process>,
+	children: HashMap<i32, Vec<i32>>,
+	boot_time: u64,
+	clk_tck: u64,
+	page_size: u64,
+	cpus: u64,
+}
+
+impl ProcTable {
+	pub fn scan(proc_root: &Path) -> ProcTable {
+		let processes: BTreeMap<i32, Process> = fs::read_dir(proc_root)
+			.map(|entries| {
+				entries
+					.filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<i32>().ok())
+					.filter_map(|pid| Process::read(proc_root, pid))
+					.map(|process| (process.pid, process))
+					.collect()
+			})
+			.unwrap_or_default();
+		let mut children: HashMap<i32, Vec<i32>> = HashMap::new();
+		for process in processes.values() {
+			children.entry(process.ppid).or_default().push(process.pid);
+		}
+
+		ProcTable {
+			processes,
+			children,
+			boot_time: boot_time(proc_root).unwrap_or(0),
+			clk_tck: clk_tck(),
+			page_size: page_size(),
+			cpus: online_cpus(),
+		}
+	}
+
+	pub fn get(&self, pid: i32) -> Option<&Process> {
+		self.processes.get(&pid)
+	}
+
+	/// All processes in ascending pid order
+	pub fn iter(&self) -> impl Iterator<Item = &Process> {
+		self.processes.values()
+	}
+
+	pub fn children(&self, pid: i32) -> &[i32] {
+		self.children.get(&pid).map(Vec::as_slice).unwrap_or_default()
+	}
+
+	/// A process and all of its descendants, in ascending pid order
+	pub fn subtree(&self, pid: i32) -> Vec<i32> {
+		let mut pids = vec![pid];
+		let mut next = 0;
+		while let Some(&pid) = pids.get(next) {
+			pids.extend_from_slice(self.children(pid));
+			next += 1;
+		}
+		pids.sort_unstable();
+		pids
+	}
+
+	/// Process start time as seconds since the unix epoch
+	pub fn start_time(&self, process: &Process) -> u64 {
+		self.boot_time + process.start_ticks / self.clk_tck
+	}
+
+	/// Total user + system cpu time of a process in seconds
+	pub fn cpu_seconds(&self, process: &Process) -> u64 {
+		process.cpu_ticks() / self.clk_tck
+	}
+
+	/// Total cpu time of several processes and the children they've reaped in seconds, without rounding each one down first
+	pub fn total_cpu_seconds<'a>(&self, processes: impl Iterator<Item = &'a Process>) -> f64 {
+		processes.map(Process::cpu_ticks_with_children).sum::<u64>() as f64 / self.clk_tck as f64
+	}
+
+	/// Online cpus on the machine, the 100% units of `cpu_percent_since`
+	pub fn cpus(&self) -> u64 {
+		self.cpus
+	}
+
+	/// Cpu usage of each process since an earlier scan `interval` ago, where 100% is one full core
+	///
+	/// Processes started since that scan count all of their cpu time, and a reused pid isn't mistaken for its predecessor.
+	pub fn cpu_percent_since(&self, earlier: &ProcTable, interval: Duration) -> HashMap<i32, f64> {
+		let interval_ticks = interval.as_secs_f64() * self.clk_tck as f64;
+		if interval_ticks <= 0.0 {
+			return HashMap::new();
+		}
+
+		self.iter()
+			.map(|process| {
+				let before = earlier
+					.get(process.pid)
+					.filter(|before| before.start_ticks == process.start_ticks)
+					.map_or(0, Process::cpu_ticks);
+				let ticks = process.cpu_ticks().saturating_sub(before);
+				(process.pid, ticks as f64 / interval_ticks * 100.0)
+			})
+			.collect()
+	}
+
+	pub fn rss_bytes(&self, process: &Process) -> u64 {
+		process.rss_pages * self.page_size
+	}
+}
+
+/// Proportional and swapped memory of a process, from /proc/<pid>/smaps_rollup
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MemoryRollup {
+	pub pss_bytes: u64,
+	pub swap_bytes: u64,
+}
+
+/// Usually only readable by root or the process's owner, and more expensive than stat, so only read for build processes
+pub fn smaps_rollup(proc_root: &Path, pid: i32) -> Option<MemoryRollup> {
+	let rollup = fs::read_to_string(proc_root.join(pid.to_string()).join("smaps_rollup")).ok()?;
+	let fields = kb_fields(&rollup);
+	Some(MemoryRollup {
+		pss_bytes: *fields.get("Pss")?,
+		swap_bytes: fields.get("Swap").copied().unwrap_or(0),
+	})
+}
+
+/// Bytes a process caused to be fetched from or sent to storage, from /proc/<pid>/io
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IoCounters {
+	pub read_bytes: u64,
+	pub write_bytes: u64,
+}
+
+impl std::ops::AddAssign for IoCounters {
+	fn add_assign(&mut self, other: IoCounters) {
+		self.read_bytes += other.read_bytes;
+		self.write_bytes += other.write_bytes;
+	}
+}
+
+/// Needs the same access as ptrace, so other users' processes are only readable as root
+pub fn io(proc_root: &Path, pid: i32) -> Option<IoCounters> {
+	let io = fs::read_to_string(proc_root.join(pid.to_string()).join("io")).ok()?;
+	let field = |name: &str| {
+		io.lines()
+			.find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+			.and_then(|value| value.trim().parse().ok())
+	};
+	Some(IoCounters {
+		read_bytes: field("read_bytes")?,
+		write_bytes: field("write_bytes")?,
+	})
+}
+
+/// System-wide memory from /proc/meminfo
+#[derive(Debug, Clone, Copy)]
+pub struct MemInfo {
+	pub total_bytes: u64,
+	pub available_bytes: u64,
+	pub swap_total_bytes: u64,
+	pub swap_free_bytes: u64,
+}
+
+pub fn meminfo(proc_root: &Path) -> Option<MemInfo> {
+	let meminfo = fs::read_to_string(proc_root.join("meminfo")).ok()?;
+	let fields = kb_fields(&meminfo);
+	Some(MemInfo {
+		total_bytes: *fields.get("MemTotal")?,
+		available_bytes: *fields.get("MemAvailable")?,
+		swap_total_bytes: fields.get("SwapTotal").copied().unwrap_or(0),
+		swap_free_bytes: fields.get("SwapFree").copied().unwrap_or(0),
+	})
+}
+
+/// Parses `Name:   1234 kB` lines into byte counts
+fn kb_fields(contents: &str) -> HashMap<&str, u64> {
+	contents
+		.lines()
+		.filter_map(|line| {
+			let (name, value) = line.split_once(':')?;
+			let kb = value.trim().strip_suffix("kB")?.trim().parse::<u64>().ok()?;
+			Some((name, kb * 1024))
+		})
+		.collect()
+}
+
+/// Environment variables a process was started with
+pub fn environ(proc_root: &Path, pid: i32) -> io::Result<HashMap<String, String>> {
+	let raw = fs::read(proc_root.join(pid.to_string()).join("environ"))?;
+	Ok(String::from_utf8_lossy(&raw)
+		.split('\0')
+		.filter_map(|var| var.split_once('='))
+		.map(|(name, value)| (name.to_string(), value.to_string()))
+		.collect())
+}
+
+/// Thread ids of a process, from /proc/<pid>/task; just the pid if that can't be read
+pub fn threads(proc_root: &Path, pid: i32) -> Vec<i32> {
+	let tids: Vec<i32> = fs::read_dir(proc_root.join(pid.to_string()).join("task"))
+		.into_iter()
+		.flatten()
+		.filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+		.collect();
+	if tids.is_empty() {
+		vec![pid]
+	} else {
+		tids
+	}
+}
+
+/// A process's start time in clock ticks after boot, which tells it apart from a later process reusing its pid
+pub fn start_ticks(proc_root: &Path, pid: i32) -> Option<u64> {
+	let stat = fs::read_to_string(proc_root.join(pid.to_string()).join("stat")).ok()?;
+	// starttime is field 22, counting from pid, and comm may contain anything
+	stat[stat.rfind(')')? + 1..].split_whitespace().nth(22 - 3)?.parse().ok()
+}
+
+fn boot_time(proc_root: &Path) -> Option<u64> {
+	fs::read_to_string(proc_root.join("stat"))
+		.ok()?
+		.lines()
+		.find_map(|line| line.strip_prefix("btime "))
+		.and_then(|btime| btime.trim().parse().ok())
+}
+
+fn clk_tck() -> u64 {
+	nix::unistd::sysconf(nix::unistd::SysconfVar::CLK_TCK)
+		.ok()
+		.flatten()
+		.filter(|&tck| tck > 0)
+		.map(|tck| tck as u64)
+		.unwrap_or(100)
+}
+
+fn page_size() -> u64 {
+	nix::unistd::sysconf(nix::unistd::SysconfVar::PAGE_SIZE)
+		.ok()
+		.flatten()
+		.filter(|&size| size > 0)
+		.map(|size| size as u64)
+		.unwrap_or(4096)
+}
+
+fn online_cpus() -> u64 {
+	nix::unistd::sysconf(nix::unistd::SysconfVar::_NPROCESSORS_ONLN)
+		.ok()
+		.flatten()
+		.filter(|&cpus| cpus > 0)
+		.map(|cpus| cpus as u64)
+		.unwrap_or(1)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn comm_with_parens_and_spaces() {
+		let stat = "4242 (a) b (c)) R 1 4242 4242 0 -1 4194560 10 0 0 0 150 30 400 60 20 0 1 0 5000 10000000 500";
+		let status = "Name:\ta) b (c)\nUid:\t1000\t30001\t30001\t30001\nGid:\t30000\t30000\t30000\t30000\n";
+		let process = Process::parse(4242, stat, status, b"cc\0-c\0x.c\0").expect("parses");
+
+		assert_eq!(process.comm, "a) b (c)");
+		assert_eq!(process.ppid, 1);
+		assert_eq!(process.uid, 30001);
+		assert_eq!(process.cmdline, "cc -c x.c");
+		assert_eq!((process.utime_ticks, process.stime_ticks), (150, 30));
+		assert_eq!(process.cpu_ticks_with_children(), 640);
+		assert_eq!(process.start_ticks, 5000);
+		assert_eq!(process.rss_pages, 500);
+	}
+
+	#[test]
+	fn kernel_thread_command() {
+		let stat = "2 (kthreadd) S 0 0 0 0 -1 2129984 0 0 0 0 0 5 0 0 20 0 1 0 2 0 0";
+		let process = Process::parse(2, stat, "Uid:\t0\t0\t0\t0\n", b"").expect("parses");
+		assert_eq!(process.command(), "[kthreadd]");
+	}
+
+	#[test]
+	fn garbled_stat() {
+		let status = "Uid:\t0\t0\t0\t0\n";
+		assert!(Process::parse(1, ") 1 (", status, b"").is_none());
+		assert!(Process::parse(1, "1 (init", status, b"").is_none());
+		assert!(Process::parse(1, "1 (init) S 0", status, b"").is_none());
+	}
+}
@@ -1,56 +1,242 @@
+mod actions;
+mod events;
+mod json;
+mod metrics;
+mod tui;
+
+use actions::{Action, Priority};
 use argh::FromArgs;
-use nix::unistd::{Pid, User};
-use std::collections::HashMap;
+use nix_scope::cgroup::BuildCgroup;
+use nix_scope::host::Host;
+use nix_scope::nixconf::NixConfig;
+use nix_scope::procfs::ProcTable;
+use nix_scope::store::StoreDb;
+use nix_scope::{short_store_path, Build, BuildError, BuildProcess, Outputs, Snapshot};
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::io::{self, Write};
-use std::process::Command;
+use std::path::PathBuf;
+use std::sync::mpsc::RecvTimeoutError;
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use termion::terminal_size;
-use users::os::unix::GroupExt;
+use tui::{KeptPriority, ListedBuild, SortKey, View};
 
 #[derive(FromArgs, Debug)]
 /// Monitor Nix build processes
 struct Args {
-	/// delay between updates in seconds
+	/// delay between updates in seconds, or with --serve-metrics the least time between two scans
 	#[argh(option, short = 'd', default = "0.25")]
 	delay: f32,
 
 	/// run only once and exit
 	#[argh(switch, short = '1')]
 	once: bool,
+
+	/// show full store paths instead of package names
+	#[argh(switch, short = 'f')]
+	full_paths: bool,
+
+	/// number of store path hash characters to show next to package names, 0 to hide them
+	#[argh(option, default = "7")]
+	hash_len: usize,
+
+	/// order builds by user, name, start, elapsed, cpu, memory or io
+	#[argh(option, short = 's', default = "SortKey::User")]
+	sort: SortKey,
+
+	/// print builds as JSON instead: one document with --once, otherwise one line per update
+	#[argh(switch)]
+	json: bool,
+
+	/// print a JSON line whenever a build starts or finishes, instead of the display
+	#[argh(switch)]
+	events: bool,
+
+	/// append --json or --events output to this file instead of printing it
+	#[argh(option, short = 'o')]
+	output: Option<PathBuf>,
+
+	/// serve Prometheus metrics on this address, e.g. 127.0.0.1:9101, instead of the display
+	#[argh(option)]
+	serve_metrics: Option<String>,
+
+	/// read processes from this directory instead of /proc, e.g. one captured from another machine
+	#[argh(option)]
+	proc_root: Option<PathBuf>,
+
+	/// read build dirs, cgroups, nix.conf, the store database and users and groups below this directory instead of /
+	#[argh(option)]
+	root: Option<PathBuf>,
 }
 
 fn main() -> io::Result<()> {
 	let args: Args = argh::from_env();
+	let host = match (args.proc_root, args.root) {
+		(proc_root, Some(root)) => Host::rooted(proc_root.unwrap_or_else(|| root.join("proc")), root),
+		(Some(proc_root), None) => Host { proc_root, ..Host::live() },
+		(None, None) => Host::live(),
+	};
+	let config = NixConfig::load(&host);
+	let store = StoreDb::open(&host);
+	let mut view = View::new(args.sort, args.full_paths, args.hash_len);
 
-	if args.once {
-		display_screen()?;
+	let delay = Duration::from_secs_f32(args.delay);
+	if let Some(addr) = &args.serve_metrics {
+		metrics::serve(addr, &host, &config, delay)?;
+	} else if args.json || args.events {
+		let mut out: Box<dyn Write> = match &args.output {
+			Some(path) => Box::new(fs::OpenOptions::new().create(true).append(true).open(path)?),
+			None => Box::new(io::stdout().lock()),
+		};
+		let written = if args.events {
+			print_events(&host, &config, delay, args.once, &mut out)
+		} else {
+			print_json(&host, &config, &store, delay, args.once, &mut out)
+		};
+		match written {
+			// the reader went away, e.g. `| head`
+			Err(err) if err.kind() == io::ErrorKind::BrokenPipe => {}
+			written => written?,
+		}
+	} else if args.once {
+		display_screen(&host, &config, &store, &view, delay)?;
 	} else {
-		loop {
-			display_screen()?;
-			sleep(Duration::from_secs_f32(args.delay));
+		let mut terminal = tui::Terminal::new()?;
+		let keys = tui::spawn_key_reader();
+		let mut snapshot = nix_scope::snapshot(&host, &config, None);
+		let mut last_scan = Instant::now();
+
+		while !tui::interrupted() {
+			let (_, height) = terminal_size()?;
+			update_view(&mut view, &snapshot);
+			terminal.draw(&view.frame(&print_screen(&host, &config, &store, &view, &snapshot), height))?;
+
+			// while paused only the elapsed times change, so redraw at the usual rate rather than spinning
+			let timeout = if view.paused {
+				delay
+			} else {
+				delay.saturating_sub(last_scan.elapsed())
+			};
+			match keys.recv_timeout(timeout) {
+				Ok(key) => {
+					if !view.handle_key(key) {
+						break;
+					}
+					if let Some((listed, action)) = view.take_action() {
+						view.message = Some(carry_out(&host, &config, &mut view, &listed, action));
+					}
+				}
+				Err(RecvTimeoutError::Timeout) => {}
+				// stdin closed, keep refreshing without input
+				Err(RecvTimeoutError::Disconnected) => sleep(timeout),
+			}
+			// priorities keep being applied to new processes while the display is paused
+			if last_scan.elapsed() >= delay && (!view.paused || !view.priorities.is_empty()) {
+				let next = nix_scope::snapshot(&host, &config, Some(&snapshot));
+				reapply_priorities(&host, &next, &mut view);
+				if !view.paused {
+					snapshot = next;
+				}
+				last_scan = Instant::now();
+			}
 		}
 	}
 
 	Ok(())
 }
 
-fn display_screen() -> io::Result<()> {
-	let (width, height) = terminal_size()?;
-	let screen = print_screen();
-	let screen = screen
+/// Tells the view what the next frame shows, for moving selections and where expanding stops
+fn update_view(view: &mut View, snapshot: &Snapshot) {
+	view.tree_deepest = deepest_tree(snapshot);
+	view.builds = listed_builds(snapshot, view)
 		.iter()
-		.take(height as usize)
-		.map(|line| {
-			format!(
-				"{:<width$}",
-				line.chars().take(width as usize).collect::<String>(),
-				width = width as usize
-			)
+		.map(|row| ListedBuild {
+			user: row.build.user.clone(),
+			id: row.build.id,
+			label: row.label.clone(),
+			pids: row.build.pids.len(),
 		})
-		.collect::<Vec<String>>()
-		.join("\n");
+		.collect();
+	view.folded.retain(|&id, folded| {
+		let Some(build) = snapshot.build_by_id(id) else {
+			return false;
+		};
+		folded.retain(|pid| build.pids.contains(pid));
+		!folded.is_empty()
+	});
+	view.foldable = match view.selected_build().and_then(|listed| snapshot.build_by_id(listed.id)) {
+		Some(build) => shown_tree(&snapshot.procs, build, view)
+			.into_iter()
+			.filter(|(node, _)| node.subtree.len() > 1)
+			.map(|(node, _)| node.pid)
+			.collect(),
+		None => Vec::new(),
+	};
+}
+
+/// Does what the user picked to the build they picked it for, describing the outcome for the status line
+fn carry_out(host: &Host, config: &NixConfig, view: &mut View, listed: &ListedBuild, action: Action) -> String {
+	if !host.is_local() {
+		return "only processes of the machine nix-scope runs on can be changed, not ones read from --proc-root or --root".to_string();
+	}
+	// what's on screen may be long out of date when paused, and misses processes started since
+	let snapshot = nix_scope::snapshot(host, config, None);
+	let Some(build) = snapshot.build_by_id(listed.id) else {
+		return format!("({}) {} finished, nothing was changed", listed.user, listed.label);
+	};
+
+	match action {
+		Action::Signal(signal) => actions::signal_build(host, &snapshot.procs, build, signal),
+		Action::Priority(priority) => {
+			// only the first change finds the build as it was started
+			let original = match view.priorities.get(&build.id) {
+				Some(kept) => kept.original,
+				None => match actions::build_priority(build) {
+					Ok(original) => original,
+					Err(err) => return format!("couldn't read the priority of ({}): {}", build.user, err),
+				},
+			};
+			match actions::set_build_priority(host, &snapshot.procs, build, priority) {
+				Ok(()) => {
+					view.priorities.insert(build.id, KeptPriority { priority, original });
+					format!("set ({}) to {}, also for processes it starts from now on", build.user, priority)
+				}
+				Err(err) => format!("couldn't set ({}) to {}: {}", build.user, priority, err),
+			}
+		}
+		Action::RestorePriority => {
+			let Some(&KeptPriority { original, .. }) = view.priorities.get(&build.id) else {
+				return format!("({}) still has the priority it was started with", build.user);
+			};
+			match actions::set_build_priority(host, &snapshot.procs, build, original) {
+				Ok(()) => {
+					view.priorities.remove(&build.id);
+					format!("set ({}) back to {}", build.user, original)
+				}
+				Err(err) => format!("couldn't set ({}) back to {}: {}", build.user, original, err),
+			}
+		}
+	}
+}
+
+/// Sets the priorities chosen for builds on processes they started since they were set, forgetting finished builds
+fn reapply_priorities(host: &Host, snapshot: &Snapshot, view: &mut View) {
+	view.priorities.retain(|&id, _| snapshot.build_by_id(id).is_some());
+	for (&id, kept) in &view.priorities {
+		if let Some(build) = snapshot.build_by_id(id) {
+			// setting it worked when it was chosen, so failures are processes that exited in the meantime
+			let _ = actions::set_build_priority(host, &snapshot.procs, build, kept.priority);
+		}
+	}
+}
+
+fn display_screen(host: &Host, config: &NixConfig, store: &StoreDb, view: &View, delay: Duration) -> io::Result<()> {
+	// cpu usage needs two samples
+	let baseline = nix_scope::snapshot(host, config, None);
+	sleep(delay);
+	let snapshot = nix_scope::snapshot(host, config, Some(&baseline));
+	let screen = tui::fit_to_terminal(&print_screen(host, config, store, view, &snapshot), terminal_size()?).join("\n");
 
 	print!("{}{}{}", termion::clear::All, termion::cursor::Goto(1, 1), screen);
 	io::stdout().flush()?;
@@ -58,20 +244,158 @@ fn display_screen() -> io::Result<()> {
 	Ok(())
 }
 
-fn print_screen() -> Vec<String> {
+fn print_json(host: &Host, config: &NixConfig, store: &StoreDb, delay: Duration, once: bool, out: &mut dyn Write) -> io::Result<()> {
+	// cpu usage needs two samples
+	let mut snapshot = nix_scope::snapshot(host, config, None);
+	loop {
+		sleep(delay);
+		snapshot = nix_scope::snapshot(host, config, Some(&snapshot));
+		let document = json::JsonSnapshot::new(host, config, store, &snapshot);
+		if once {
+			serde_json::to_writer_pretty(&mut *out, &document)?;
+		} else {
+			serde_json::to_writer(&mut *out, &document)?;
+		}
+		writeln!(out)?;
+		out.flush()?;
+		if once {
+			return Ok(());
+		}
+	}
+}
+
+fn print_events(host: &Host, config: &NixConfig, delay: Duration, once: bool, out: &mut dyn Write) -> io::Result<()> {
+	let mut previous: Option<Snapshot> = None;
+	loop {
+		let snapshot = nix_scope::snapshot(host, config, previous.as_ref());
+		for event in events::build_events(previous.as_ref().map(|previous| previous.builds.as_slice()), &snapshot.builds) {
+			serde_json::to_writer(&mut *out, &event)?;
+			writeln!(out)?;
+		}
+		out.flush()?;
+		if once {
+			return Ok(());
+		}
+		previous = Some(snapshot);
+		sleep(delay);
+	}
+}
+
+/// A build as shown on screen
+struct BuildRow<'a> {
+	build: &'a Build,
+	label: String,
+	selected: bool,
+	/// Set from the UI and kept applied to new processes
+	priority: Option<Priority>,
+}
+
+/// The builds matching the filter, in the chosen order
+fn listed_builds<'a>(snapshot: &'a Snapshot, view: &View) -> Vec<BuildRow<'a>> {
+	let filter = view.filter.to_lowercase();
+	let mut builds: Vec<BuildRow> = snapshot
+		.builds
+		.iter()
+		.map(|build| BuildRow {
+			build,
+			label: build_label(&build.outputs, view),
+			selected: view.selected == Some(build.id),
+			priority: view.priorities.get(&build.id).map(|kept| kept.priority),
+		})
+		.filter(|row| row.build.user.to_lowercase().contains(&filter) || row.label.to_lowercase().contains(&filter))
+		.collect();
+	sort_builds(&mut builds, view.sort);
+	builds
+}
+
+fn sort_builds(builds: &mut [BuildRow], sort: SortKey) {
+	builds.sort_by(|a, b| {
+		let by_key = match sort {
+			SortKey::User => a.build.user.cmp(&b.build.user),
+			SortKey::Name => a.label.cmp(&b.label),
+			SortKey::Start => b.build.start_time.cmp(&a.build.start_time),
+			SortKey::Elapsed => a.build.start_time.unwrap_or(u64::MAX).cmp(&b.build.start_time.unwrap_or(u64::MAX)),
+			SortKey::Cpu => b.build.cpu_percent.unwrap_or(0.0).total_cmp(&a.build.cpu_percent.unwrap_or(0.0)),
+			SortKey::Memory => b.build.rss_bytes.cmp(&a.build.rss_bytes),
+			SortKey::Io => {
+				let total = |build: &Build| build.io_rates.map_or(0.0, |(read, write)| read + write);
+				total(b.build).total_cmp(&total(a.build))
+			}
+		};
+		by_key
+			.then_with(|| a.build.first_seen.cmp(&b.build.first_seen))
+			.then_with(|| a.build.user.cmp(&b.build.user))
+	});
+}
+
+fn print_screen(host: &Host, config: &NixConfig, store: &StoreDb, view: &View, snapshot: &Snapshot) -> Vec<String> {
 	let mut lines = Vec::new();
-	let processes = get_processes();
+	let builds = listed_builds(snapshot, view);
 
-	lines.push(format!("Nix build summary ({} processes)", processes.len()));
-	for (user, (path, pids)) in &processes {
-		lines.push(format!("    {:4} → {}", pids.len(), path));
+	lines.push(format!(
+		"Nix build summary ({} processes) · max-jobs {} · cores {} · sandbox {} · {}",
+		snapshot.builds.len(),
+		config.max_jobs,
+		match config.cores {
+			0 => "all".to_string(),
+			cores => cores.to_string(),
+		},
+		config.sandbox,
+		if config.auto_allocate_uids {
+			"auto-allocated uids".to_string()
+		} else if config.build_users_group.is_empty() {
+			"single-user".to_string()
+		} else {
+			format!("group {}", config.build_users_group)
+		}
+	));
+	if let Some(meminfo) = snapshot.meminfo {
+		let mut memory = format!(
+			"Memory: {} used of {}, {} available",
+			format_bytes(meminfo.total_bytes.saturating_sub(meminfo.available_bytes)),
+			format_bytes(meminfo.total_bytes),
+			format_bytes(meminfo.available_bytes)
+		);
+		if meminfo.swap_total_bytes > 0 {
+			memory.push_str(&format!(
+				" · swap {} used of {}",
+				format_bytes(meminfo.swap_total_bytes.saturating_sub(meminfo.swap_free_bytes)),
+				format_bytes(meminfo.swap_total_bytes)
+			));
+		}
+		lines.push(memory);
+	}
+	let now = unix_now();
+	for row in &builds {
+		lines.push(format!(
+			"  {} {:4} {:>8} → {}",
+			if row.selected { "▶" } else { " " },
+			row.build.pids.len(),
+			format_elapsed(row.build, now),
+			row.label
+		));
 	}
 	lines.push("".to_string());
 	lines.push(" * * * ".to_string());
 	lines.push("".to_string());
 
-	for (user, (path, pids)) in &processes {
-		let (info, ps_output) = per_output_infos(user, pids, path);
+	for row in &builds {
+		let cgroup = snapshot.cgroups.iter().find(|cgroup| cgroup.pids.contains(&row.build.id.builder));
+		let drv = row
+			.build
+			.outputs
+			.as_ref()
+			.ok()
+			.and_then(|outputs| outputs.paths.first())
+			.and_then(|(_, path)| store.drv_for_output(path));
+		let drv = drv.map(|drv| {
+			if view.full_paths {
+				drv
+			} else {
+				short_store_path(&drv, view.hash_len)
+			}
+		});
+		let (info, ps_output) = per_output_infos(host, row, drv.as_deref(), snapshot, cgroup, now, view);
 		lines.push(info);
 		lines.extend(ps_output.lines().map(String::from));
 	}
@@ -79,94 +403,326 @@ fn print_screen() -> Vec<String> {
 	lines
 }
 
-fn get_processes() -> HashMap<String, (String, Vec<i32>)> {
-	let mut processes = HashMap::new();
-	let build_users: std::collections::HashSet<_> = build_users().into_iter().collect();
-
-	if let Ok(output) = Command::new("ps")
-		.args(&["-o", "user=,pid=", "-u"])
-		.arg(&build_users.into_iter().collect::<Vec<_>>().join(","))
-		.output()
-	{
-		let user_pid_map = String::from_utf8_lossy(&output.stdout)
-			.lines()
-			.filter_map(|line| {
-				let parts: Vec<&str> = line.split_whitespace().collect();
-				if parts.len() >= 2 {
-					Some((parts[0].to_string(), parts[1].parse::<i32>().ok()?))
-				} else {
-					None
-				}
-			})
-			.fold(HashMap::new(), |mut map, (user, pid)| {
-				map.entry(user).or_insert_with(Vec::new).push(pid);
-				map
-			});
-
-		for (user, pids) in user_pid_map {
-			if !pids.is_empty() {
-				let path = get_out_path(&user, pids[0]);
-				assert!(!path.is_empty());
-				processes.insert(user, (path, pids));
+/// Label for a build, or the reason we couldn't work out what it is building
+fn build_label(outputs: &Result<Outputs, BuildError>, view: &View) -> String {
+	match outputs {
+		Ok(outputs) => outputs.label(view.full_paths, view.hash_len),
+		Err(err) => format!("({})", err),
+	}
+}
+
+fn per_output_infos(
+	host: &Host,
+	row: &BuildRow,
+	drv: Option<&str>,
+	snapshot: &Snapshot,
+	cgroup: Option<&BuildCgroup>,
+	now: u64,
+	view: &View,
+) -> (String, String) {
+	let (build, procs) = (row.build, &snapshot.procs);
+	let marker = if row.selected { "▶▶" } else { "::" };
+	let mut info = match drv {
+		Some(drv) => format!("{} ({}) {} → {}", marker, build.user, drv, row.label),
+		None => format!("{} ({}) → {}", marker, build.user, row.label),
+	};
+	if let Some(priority) = row.priority {
+		info.push_str(&format!(" · {}", priority));
+	}
+	if build.start_time.is_some() {
+		info.push_str(&format!(" · elapsed {}", format_elapsed(build, now)));
+	}
+	if let Some(cpu_percent) = build.cpu_percent {
+		info.push_str(&format!(" · cpu {:.0}%", cpu_percent));
+		// the cores we count are ours, not those of the machine a captured tree came from
+		if host.is_local() {
+			info.push_str(&format!(" of {} cores", procs.cpus()));
+		}
+	}
+	info.push_str(&format!(" · rss {}", format_bytes(build.rss_bytes)));
+	if let Some(pss) = build.pss_bytes {
+		info.push_str(&format!(" pss {}", format_bytes(pss)));
+	}
+	if let Some(swap) = build.swap_bytes.filter(|&swap| swap > 0) {
+		info.push_str(&format!(" swap {}", format_bytes(swap)));
+	}
+	if let Some((read, write)) = build.io_rates {
+		let total = build.io_total;
+		info.push_str(&format!(
+			" · io r {}/s w {}/s (total r {} w {})",
+			format_bytes(read as u64),
+			format_bytes(write as u64),
+			format_bytes(total.read_bytes),
+			format_bytes(total.write_bytes)
+		));
+	}
+	if let Some(cgroup) = cgroup {
+		let stats = cgroup.stats();
+		let mut parts = Vec::new();
+		if let Some(memory) = stats.memory_current {
+			parts.push(format!("mem {}", format_bytes(memory)));
+		}
+		if let Some(usec) = stats.cpu_usage_usec {
+			parts.push(format!("cpu {}", format_cputime(usec / 1_000_000)));
+		}
+		if let (Some(read), Some(write)) = (stats.io_read_bytes, stats.io_write_bytes) {
+			parts.push(format!("io r {} w {}", format_bytes(read), format_bytes(write)));
+		}
+		info.push_str(&format!(" [cgroup: {}]", parts.join(", ")));
+	}
+	let mut ps_output = String::new();
+	for (output, path) in build.outputs.iter().flat_map(|outputs| &outputs.paths) {
+		ps_output.push_str(&format!("    {:>8}: {}\n", output, path));
+	}
+	// auto-allocated uids run to 9 digits
+	let uid_width = build
+		.processes
+		.iter()
+		.map(|process| process.uid.to_string().len())
+		.fold(5, usize::max);
+	ps_output.push_str(&format!(
+		" {:>uid_width$} {:>7} {:>7} {:>5} {:>6} {:>5} {:>8} {}\n",
+		"UID", "PID", "PPID", "%CPU", "RSS", "STIME", "TIME", "CMD"
+	));
+	let processes: HashMap<i32, &BuildProcess> = build.processes.iter().map(|process| (process.pid, process)).collect();
+	let cursor = view.cursor.filter(|_| row.selected);
+	for (node, folded) in shown_tree(procs, build, view) {
+		let Some(process) = processes.get(&node.pid) else {
+			continue;
+		};
+		let cpu_percent = process.cpu_percent.map_or("-".to_string(), |percent| format!("{:.1}", percent));
+		let mut command = process.command.clone();
+		if node.subtree.len() > 1 {
+			let mut totals = vec![format!("{} procs", node.subtree.len())];
+			if let Some(percent) = snapshot.cpu_percent(&node.subtree) {
+				totals.push(format!("cpu {:.1}%", percent));
 			}
+			let rss: u64 = node
+				.subtree
+				.iter()
+				.filter_map(|pid| processes.get(pid))
+				.map(|process| process.rss_bytes)
+				.sum();
+			totals.push(format!("rss {}", format_bytes(rss)));
+			command = format!("{}{} [{}]", if folded { "+ " } else { "" }, command, totals.join(", "));
 		}
+		ps_output.push_str(&format!(
+			"{}{:>uid_width$} {:>7} {:>7} {:>5} {:>6} {:>5} {:>8} {}{}\n",
+			if cursor == Some(node.pid) { "▶" } else { " " },
+			process.uid,
+			process.pid,
+			process.ppid,
+			cpu_percent,
+			format_bytes(process.rss_bytes),
+			format_stime(process.start_time),
+			format_cputime(process.cpu_seconds),
+			node.prefix,
+			command
+		));
 	}
 
-	processes
+	(info, ps_output)
 }
 
-fn build_users() -> Vec<String> {
-	users::get_group_by_name("nixbld")
-		.map(|group| group.members().iter().map(|u| u.to_string_lossy().into_owned()).collect())
-		.unwrap_or_default()
+/// One line of a build's process tree
+struct TreeRow {
+	pid: i32,
+	/// 0 for processes whose parent isn't part of the build, like the builder
+	depth: usize,
+	/// pstree-style branches drawn in front of the command
+	prefix: String,
+	/// The process and its descendants within the build
+	subtree: Vec<i32>,
 }
 
-fn get_out_path(user: &str, pid: i32) -> String {
-	// Try to get out path from /proc environment first
-	if let Ok(env_content) = fs::read_to_string(format!("/proc/{}/environ", pid)) {
-		let vars: Vec<&str> = env_content.split('\0').collect();
-		if let Some(out_var) = vars.iter().find(|v| v.starts_with("out=")) {
-			if let Some(out_path) = out_var.strip_prefix("out=") {
-				if !out_path.is_empty() {
-					return out_path.to_string();
-				}
-			}
+/// The rows of a build's process tree left once folded subtrees are hidden, with whether each row is folded
+fn shown_tree(procs: &ProcTable, build: &Build, view: &View) -> Vec<(TreeRow, bool)> {
+	let folded_here = view.folded.get(&build.id);
+	let mut shown = Vec::new();
+	let mut folded_at = None;
+	for node in process_tree(procs, &build.pids) {
+		if folded_at.is_some_and(|depth| node.depth > depth) {
+			continue;
+		}
+		let folded =
+			node.subtree.len() > 1 && (view.tree_depth == Some(node.depth) || folded_here.is_some_and(|folded| folded.contains(&node.pid)));
+		folded_at = folded.then_some(node.depth);
+		shown.push((node, folded));
+	}
+	shown
+}
+
+/// Arranges a build's processes by parent, depth first with siblings in pid order
+fn process_tree(procs: &ProcTable, pids: &[i32]) -> Vec<TreeRow> {
+	let in_build: HashSet<i32> = pids.iter().copied().collect();
+	let mut rows = Vec::new();
+	for process in pids.iter().filter_map(|&pid| procs.get(pid)) {
+		if !in_build.contains(&process.ppid) {
+			push_subtree(procs, &in_build, process.pid, 0, String::new(), String::new(), &mut rows);
+		}
+	}
+	rows
+}
+
+fn push_subtree(procs: &ProcTable, in_build: &HashSet<i32>, pid: i32, depth: usize, prefix: String, indent: String, rows: &mut Vec<TreeRow>) {
+	let index = rows.len();
+	rows.push(TreeRow {
+		pid,
+		depth,
+		prefix,
+		subtree: Vec::new(),
+	});
+	let children: Vec<i32> = procs
+		.children(pid)
+		.iter()
+		.copied()
+		.filter(|child| in_build.contains(child))
+		.collect();
+	for (i, &child) in children.iter().enumerate() {
+		let (branch, continuation) = if i + 1 == children.len() {
+			("└─", "  ")
+		} else {
+			("├─", "│ ")
+		};
+		let (prefix, child_indent) = (format!("{}{}", indent, branch), format!("{}{}", indent, continuation));
+		push_subtree(procs, in_build, child, depth + 1, prefix, child_indent, rows);
+	}
+	rows[index].subtree = rows[index..].iter().map(|row| row.pid).collect();
+}
+
+/// Deepest level of any build's process tree, so expanding the trees knows where to stop
+fn deepest_tree(snapshot: &Snapshot) -> usize {
+	snapshot
+		.builds
+		.iter()
+		.flat_map(|build| process_tree(&snapshot.procs, &build.pids))
+		.map(|row| row.depth)
+		.max()
+		.unwrap_or(0)
+}
+
+/// Formats a start time like ps's STIME column: HH:MM today, MonDD this year, else the year
+fn format_stime(start: u64) -> String {
+	let local = |secs: u64| {
+		let time = secs as nix::libc::time_t;
+		// SAFETY: localtime_r only writes to the tm we hand it
+		unsafe {
+			let mut tm = std::mem::zeroed::<nix::libc::tm>();
+			nix::libc::localtime_r(&time, &mut tm);
+			tm
 		}
+	};
+	let now = unix_now();
+	let (start_tm, now_tm) = (local(start), local(now));
+
+	if now.saturating_sub(start) < 24 * 60 * 60 {
+		format!("{:02}:{:02}", start_tm.tm_hour, start_tm.tm_min)
+	} else if start_tm.tm_year == now_tm.tm_year {
+		const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+		format!("{}{:02}", MONTHS[start_tm.tm_mon as usize % 12], start_tm.tm_mday)
+	} else {
+		format!("{}", start_tm.tm_year + 1900)
 	}
+}
 
-	let build_dir = get_build_dir(user).unwrap_or_else(|_| "(unknown)".to_string());
-	get_out_from_env_vars(&build_dir).unwrap_or(build_dir)
+/// Formats cumulative cpu time like ps's TIME column: [DD-]HH:MM:SS
+fn format_cputime(secs: u64) -> String {
+	let (days, hours, mins, secs) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60, secs % 60);
+	if days > 0 {
+		format!("{}-{:02}:{:02}:{:02}", days, hours, mins, secs)
+	} else {
+		format!("{:02}:{:02}:{:02}", hours, mins, secs)
+	}
 }
 
-fn get_build_dir(user: &str) -> io::Result<String> {
-	let output = Command::new("sh")
-		.arg("-c")
-		.arg(format!(
-			"find -L /tmp -maxdepth 1 -user {} -exec stat --printf '%Z:%n\\n' '{{}}' ';' | sort -n | tail -n1",
-			user
-		))
-		.output()?;
+/// Wall-clock time a build has been running for, `-` if we don't know when it started
+fn format_elapsed(build: &Build, now: u64) -> String {
+	build
+		.start_time
+		.map_or("-".to_string(), |start| format_duration(now.saturating_sub(start)))
+}
 
-	let last_line = String::from_utf8_lossy(&output.stdout).lines().last().unwrap_or("").to_string();
-	Ok(last_line.split(':').last().unwrap_or("").to_string())
+/// Formats a duration compactly like `1h04m12s`, dropping the seconds once it runs into days
+fn format_duration(secs: u64) -> String {
+	let (days, hours, mins, secs) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60, secs % 60);
+	if days > 0 {
+		format!("{}d{:02}h{:02}m", days, hours, mins)
+	} else if hours > 0 {
+		format!("{}h{:02}m{:02}s", hours, mins, secs)
+	} else if mins > 0 {
+		format!("{}m{:02}s", mins, secs)
+	} else {
+		format!("{}s", secs)
+	}
 }
 
-fn get_out_from_env_vars(build_dir: &str) -> Option<String> {
-	let env_vars = fs::read_to_string(format!("{}/env-vars", build_dir)).ok()?;
-	env_vars
-		.lines()
-		.find(|line| line.starts_with("declare -x out="))
-		.and_then(|line| line.split('"').nth(1))
-		.map(|s| s.to_string())
+/// Current time as seconds since the unix epoch
+fn unix_now() -> u64 {
+	std::time::SystemTime::now()
+		.duration_since(std::time::UNIX_EPOCH)
+		.map(|d| d.as_secs())
+		.unwrap_or(0)
 }
 
-fn per_output_infos(user: &str, pids: &[i32], path: &str) -> (String, String) {
-	let info = format!(":: ({}) → {}", user, path);
-	let ps_output = Command::new("ps")
-		.args(&["-o", "uid,pid,ppid,stime,time,command", "-U", user])
-		.output()
-		.map(|output| String::from_utf8_lossy(&output.stdout).into_owned())
-		.unwrap_or_default();
+/// Formats a byte count with a binary unit suffix, like `ls -h`
+fn format_bytes(bytes: u64) -> String {
+	const UNITS: [&str; 6] = ["B", "K", "M", "G", "T", "P"];
+	let mut value = bytes as f64;
+	let mut unit = 0;
+	while value >= 1024.0 && unit < UNITS.len() - 1 {
+		value /= 1024.0;
+		unit += 1;
+	}
+	if unit == 0 {
+		format!("{}{}", bytes, UNITS[0])
+	} else {
+		format!("{:.1}{}", value, UNITS[unit])
+	}
+}
 
-	(info, ps_output)
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use actions::IoClass;
+	use nix_scope::BuildId;
+	use std::path::Path;
+
+	#[test]
+	fn priority_outlives_a_lower_pid_joining_the_build() {
+		let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/builds");
+		let host = Host::rooted(root.join("proc"), root);
+		let snapshot = nix_scope::snapshot(&host, &NixConfig::load(&host), None);
+		// cc got pid 150 after the pid counter wrapped, below its builder's 200
+		assert_eq!(snapshot.build("nixbld1").map(|build| build.pids[0]), Some(150));
+
+		let kept = KeptPriority {
+			priority: Priority {
+				nice: 19,
+				io_class: IoClass::Idle,
+			},
+			original: Priority {
+				nice: 0,
+				io_class: IoClass::None,
+			},
+		};
+		let set = BuildId {
+			builder: 200,
+			start_ticks: 5000,
+		};
+		// pid 200 back when it was another build's builder
+		let finished = BuildId {
+			builder: 200,
+			start_ticks: 4000,
+		};
+		let mut view = View::new(SortKey::User, false, 7);
+		view.priorities.insert(set, kept);
+		view.priorities.insert(finished, kept);
+		reapply_priorities(&host, &snapshot, &mut view);
+
+		assert!(view.priorities.contains_key(&set));
+		assert!(!view.priorities.contains_key(&finished));
+		let rows = listed_builds(&snapshot, &view);
+		let row = rows.iter().find(|row| row.build.user == "nixbld1").expect("listed");
+		assert_eq!(row.priority, Some(kept.priority));
+	}
 }
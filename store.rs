@@ -0,0 +1,57 @@
+use crate::host::Host;
+use rusqlite::{Connection, OpenFlags, OptionalExtension};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+/// Read-only view of the local nix store's database, used to map output paths back to their derivations
+pub struct StoreDb {
+	conn: Option<Connection>,
+	/// An output path always belongs to the same derivation, so lookups never go stale
+	drv_cache: RefCell<HashMap<String, String>>,
+}
+
+impl StoreDb {
+	/// Opens $NIX_STATE_DIR/db/db.sqlite (default /nix/var/nix/db/db.sqlite), both taken as paths on the host;
+	/// lookups return None if it can't be read
+	pub fn open(host: &Host) -> StoreDb {
+		let state_dir = host.path(std::env::var_os("NIX_STATE_DIR").map_or_else(|| PathBuf::from("/nix/var/nix"), PathBuf::from));
+		let conn = Connection::open_with_flags(
+			state_dir.join("db/db.sqlite"),
+			OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+		)
+		.ok();
+		if let Some(conn) = &conn {
+			// the daemon holds write locks while registering paths; wait briefly rather than failing the lookup
+			let _ = conn.busy_timeout(std::time::Duration::from_millis(50));
+		}
+
+		StoreDb {
+			conn,
+			drv_cache: RefCell::new(HashMap::new()),
+		}
+	}
+
+	/// The .drv that produces `out_path`, if the store knows about it
+	pub fn drv_for_output(&self, out_path: &str) -> Option<String> {
+		if let Some(drv) = self.drv_cache.borrow().get(out_path) {
+			return Some(drv.clone());
+		}
+
+		let drv: Option<String> = self.conn.as_ref().and_then(|conn| {
+			conn.query_row(
+				"SELECT v.path FROM DerivationOutputs d JOIN ValidPaths v ON v.id = d.drv WHERE d.path = ? ORDER BY d.drv DESC LIMIT 1",
+				[out_path],
+				|row| row.get(0),
+			)
+			.optional()
+			.ok()
+			.flatten()
+		});
+		// a failed lookup may succeed once the daemon has registered the derivation, so only cache hits
+		if let Some(drv) = &drv {
+			self.drv_cache.borrow_mut().insert(out_path.to_string(), drv.clone());
+		}
+		drv
+	}
+}
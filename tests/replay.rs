@@ -0,0 +1,81 @@
+//! Discovery run against a captured tree in tests/fixtures/builds instead of the live system
+
+use nix_scope::host::Host;
+use nix_scope::nixconf::NixConfig;
+use nix_scope::{Build, BuildId, Snapshot};
+use std::path::{Path, PathBuf};
+
+fn fixture() -> PathBuf {
+	Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/builds")
+}
+
+fn snapshot() -> Snapshot {
+	let root = fixture();
+	let host = Host::rooted(root.join("proc"), root);
+	let config = NixConfig::load(&host);
+	nix_scope::snapshot(&host, &config, None)
+}
+
+fn build<'a>(snapshot: &'a Snapshot, user: &str) -> &'a Build {
+	snapshot.build(user).unwrap_or_else(|| panic!("no build for {}", user))
+}
+
+#[test]
+fn finds_every_build() {
+	let snapshot = snapshot();
+	let users: Vec<&str> = snapshot.builds.iter().map(|build| build.user.as_str()).collect();
+	assert_eq!(users, ["nixbld1", "nixbld2", "slot-2"]);
+}
+
+#[test]
+fn build_user_from_passwd_files() {
+	let snapshot = snapshot();
+	let build = build(&snapshot, "nixbld1");
+	assert_eq!(build.uid, 30001);
+	assert_eq!(build.pids, [150, 200, 201]);
+	let commands: Vec<&str> = build.processes.iter().map(|process| process.command.as_str()).collect();
+	assert_eq!(commands, ["cc -c hello.c", "bash -e /nix/store/builder.sh", "make -j4"]);
+
+	let outputs = build.outputs.as_ref().expect("outputs from the builder's environ");
+	assert_eq!(outputs.label(false, 7), "hello 2.12 0123456");
+	assert_eq!(outputs.build_dir, None);
+}
+
+#[test]
+fn builder_is_not_the_lowest_pid() {
+	let snapshot = snapshot();
+	// cc got a lower pid than the builder after the pid counter wrapped around
+	let build = build(&snapshot, "nixbld1");
+	assert_eq!(
+		build.id,
+		BuildId {
+			builder: 200,
+			start_ticks: 5000
+		}
+	);
+	assert_eq!(snapshot.build_by_id(build.id).map(|build| build.user.as_str()), Some("nixbld1"));
+}
+
+#[test]
+fn auto_allocated_uid_slot() {
+	let snapshot = snapshot();
+	let build = build(&snapshot, "slot-2");
+	assert_eq!(build.uid, 872546304);
+	assert_eq!(build.pids, [300]);
+	let outputs = build.outputs.as_ref().expect("outputs from the builder's environ");
+	assert_eq!(outputs.name.as_deref(), Some("auto-1.0"));
+}
+
+#[test]
+fn outputs_from_env_vars_when_environ_is_empty() {
+	let snapshot = snapshot();
+	let build = build(&snapshot, "nixbld2");
+	let outputs = build.outputs.as_ref().expect("outputs from env-vars");
+	let names: Vec<&str> = outputs.paths.iter().map(|(name, _)| name.as_str()).collect();
+	assert_eq!(names, ["out", "dev", "doc"]);
+	assert_eq!(outputs.paths[1].1, "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-multi-1.0-dev");
+	assert_eq!(
+		outputs.build_dir.as_deref(),
+		Some(fixture().join("tmp/nix-build-multi-1.0.drv-0/build").as_path())
+	);
+}
@@ -0,0 +1,512 @@
+use crate::actions::{Action, IoClass, Priority};
+use nix::sys::signal::{self, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use nix::sys::termios;
+use nix_scope::BuildId;
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Stdout, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Mutex;
+use std::thread;
+use termion::cursor::{self, HideCursor};
+use termion::event::Key;
+use termion::input::TermRead;
+use termion::raw::{IntoRawMode, RawTerminal};
+use termion::screen::{self, AlternateScreen};
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_signal(_: nix::libc::c_int) {
+	INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Whether we've been asked to exit by SIGINT, SIGTERM or SIGHUP
+pub fn interrupted() -> bool {
+	INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Full-screen display on the alternate screen that only rewrites lines which changed since the last frame
+///
+/// The terminal is restored when this is dropped, and by a panic hook before the panic message is printed.
+pub struct Terminal {
+	out: HideCursor<AlternateScreen<RawTerminal<Stdout>>>,
+	/// Lines currently on screen, already fitted to `size`
+	shown: Vec<String>,
+	size: (u16, u16),
+}
+
+impl Terminal {
+	pub fn new() -> io::Result<Terminal> {
+		let handler = SigAction::new(SigHandler::Handler(on_signal), SaFlags::empty(), SigSet::empty());
+		for sig in [Signal::SIGINT, Signal::SIGTERM, Signal::SIGHUP] {
+			// SAFETY: the handler only stores to an atomic
+			unsafe { signal::sigaction(sig, &handler) }.map_err(io::Error::from)?;
+		}
+
+		// RawTerminal only restores the mode once unwinding drops it, after the panic message was already mangled
+		let original_mode = Mutex::new(termios::tcgetattr(nix::libc::STDOUT_FILENO).map_err(io::Error::from)?);
+		let default_hook = std::panic::take_hook();
+		std::panic::set_hook(Box::new(move |info| {
+			if let Ok(mode) = original_mode.lock() {
+				let _ = termios::tcsetattr(nix::libc::STDOUT_FILENO, termios::SetArg::TCSANOW, &mode);
+			}
+			let mut stdout = io::stdout();
+			let _ = write!(stdout, "{}{}", screen::ToMainScreen, cursor::Show);
+			let _ = stdout.flush();
+			default_hook(info);
+		}));
+
+		Ok(Terminal {
+			out: HideCursor::from(AlternateScreen::from(io::stdout().into_raw_mode()?)),
+			shown: Vec::new(),
+			size: (0, 0),
+		})
+	}
+
+	pub fn draw(&mut self, lines: &[String]) -> io::Result<()> {
+		let size = termion::terminal_size()?;
+		let frame = fit_to_terminal(lines, size);
+
+		if size != self.size {
+			write!(self.out, "{}", termion::clear::All)?;
+			self.shown.clear();
+			self.size = size;
+		}
+		for (row, line) in frame.iter().enumerate() {
+			if self.shown.get(row) != Some(line) {
+				write!(self.out, "{}{}", cursor::Goto(1, row as u16 + 1), line)?;
+			}
+		}
+		// blank out rows left over from a longer previous frame
+		let blank = " ".repeat(size.0 as usize);
+		for row in frame.len()..self.shown.len() {
+			write!(self.out, "{}{}", cursor::Goto(1, row as u16 + 1), blank)?;
+		}
+		self.out.flush()?;
+
+		self.shown = frame;
+		Ok(())
+	}
+}
+
+/// Cuts lines to the terminal's size and pads them so each one overwrites whatever was there before
+pub fn fit_to_terminal(lines: &[String], (width, height): (u16, u16)) -> Vec<String> {
+	lines
+		.iter()
+		.take(height as usize)
+		.map(|line| {
+			format!(
+				"{:<width$}",
+				line.chars().take(width as usize).collect::<String>(),
+				width = width as usize
+			)
+		})
+		.collect()
+}
+
+/// Reads keys on a background thread so the main loop can wait for input and the refresh timer at once
+pub fn spawn_key_reader() -> Receiver<Key> {
+	let (tx, rx) = mpsc::channel();
+	thread::spawn(move || {
+		for key in io::stdin().keys().map_while(Result::ok) {
+			if tx.send(key).is_err() {
+				break;
+			}
+		}
+	});
+	rx
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortKey {
+	User,
+	Name,
+	/// Most recently started first
+	Start,
+	/// Longest running first
+	Elapsed,
+	Cpu,
+	Memory,
+	/// Highest combined read and write rate first
+	Io,
+}
+
+impl SortKey {
+	const ALL: [SortKey; 7] = [
+		SortKey::User,
+		SortKey::Name,
+		SortKey::Start,
+		SortKey::Elapsed,
+		SortKey::Cpu,
+		SortKey::Memory,
+		SortKey::Io,
+	];
+
+	fn next(self) -> SortKey {
+		let index = SortKey::ALL.iter().position(|&key| key == self).unwrap_or(0);
+		SortKey::ALL[(index + 1) % SortKey::ALL.len()]
+	}
+
+	pub fn name(self) -> &'static str {
+		match self {
+			SortKey::User => "user",
+			SortKey::Name => "name",
+			SortKey::Start => "start",
+			SortKey::Elapsed => "elapsed",
+			SortKey::Cpu => "cpu",
+			SortKey::Memory => "memory",
+			SortKey::Io => "io",
+		}
+	}
+}
+
+impl std::str::FromStr for SortKey {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<SortKey, String> {
+		SortKey::ALL.into_iter().find(|key| key.name() == s).ok_or_else(|| {
+			let names: Vec<_> = SortKey::ALL.iter().map(|key| key.name()).collect();
+			format!("unknown sort key {:?}, expected one of {}", s, names.join(", "))
+		})
+	}
+}
+
+/// A build as listed in the last frame, for selecting it and acting on it
+#[derive(Debug, Clone)]
+pub struct ListedBuild {
+	pub user: String,
+	/// So an action isn't carried out on a different build that took over the same user
+	pub id: BuildId,
+	pub label: String,
+	pub pids: usize,
+}
+
+/// Signals that can be sent to a build, with the key choosing each in the signal prompt
+const SIGNALS: [(char, Signal); 4] = [
+	('t', Signal::SIGTERM),
+	('k', Signal::SIGKILL),
+	('s', Signal::SIGSTOP),
+	('c', Signal::SIGCONT),
+];
+
+/// Priorities a build can be given, with the key choosing each in the priority prompt
+const PRIORITIES: [(char, &str, Priority); 3] = [
+	(
+		'l',
+		"low",
+		Priority {
+			nice: 10,
+			io_class: IoClass::BestEffort(7),
+		},
+	),
+	(
+		'i',
+		"idle",
+		Priority {
+			nice: 19,
+			io_class: IoClass::Idle,
+		},
+	),
+	(
+		'h',
+		"high",
+		Priority {
+			nice: -5,
+			io_class: IoClass::BestEffort(0),
+		},
+	),
+];
+
+/// Key in the priority prompt that puts a build back to the priority it had before
+const RESTORE_PRIORITY: char = 'n';
+
+/// A priority kept applied to a build and its new processes
+#[derive(Debug, Clone, Copy)]
+pub struct KeptPriority {
+	pub priority: Priority,
+	/// What the builder had before the build's priority was first changed, for going back to it
+	pub original: Priority,
+}
+
+/// Choice being asked for about a build, in place of the help line
+#[derive(Debug)]
+enum Prompt {
+	Signal,
+	/// Sending it once the user confirms
+	ConfirmSignal(Signal),
+	Priority,
+}
+
+/// What the user has chosen to look at, changed by keypresses between refreshes
+#[derive(Debug)]
+pub struct View {
+	pub paused: bool,
+	pub sort: SortKey,
+	pub full_paths: bool,
+	pub hash_len: usize,
+	/// Case-insensitive substring builds must match on their user or label to be shown
+	pub filter: String,
+	/// Deepest level of the process trees to show, collapsing everything below; None shows them whole
+	pub tree_depth: Option<usize>,
+	/// Deepest level any build's process tree had in the last frame, where expanding stops
+	pub tree_deepest: usize,
+	/// Processes whose descendants are folded away, by build
+	pub folded: HashMap<BuildId, HashSet<i32>>,
+	/// Processes of the selected build shown with descendants in the last frame, in the order they were shown
+	pub foldable: Vec<i32>,
+	/// One of `foldable`, which enter folds or unfolds
+	pub cursor: Option<i32>,
+	/// Builds in the order they were listed in the last frame
+	pub builds: Vec<ListedBuild>,
+	/// Build actions apply to
+	pub selected: Option<BuildId>,
+	/// Outcome of the last action, shown in place of the help line until the next keypress
+	pub message: Option<String>,
+	/// Priorities kept applied to builds
+	pub priorities: HashMap<BuildId, KeptPriority>,
+	/// Build a choice is being asked for about
+	prompt: Option<(ListedBuild, Prompt)>,
+	/// Action the user picked, waiting to be carried out by the main loop
+	action: Option<(ListedBuild, Action)>,
+	/// Filter being typed, shown in place of the help line until enter or escape
+	filter_prompt: Option<String>,
+	/// First line of the build list shown below the title
+	scroll: usize,
+	/// Rows the build list had in the last frame, for paging
+	page: usize,
+}
+
+impl View {
+	pub fn new(sort: SortKey, full_paths: bool, hash_len: usize) -> View {
+		View {
+			paused: false,
+			sort,
+			full_paths,
+			hash_len,
+			filter: String::new(),
+			tree_depth: None,
+			tree_deepest: 0,
+			folded: HashMap::new(),
+			foldable: Vec::new(),
+			cursor: None,
+			builds: Vec::new(),
+			selected: None,
+			message: None,
+			priorities: HashMap::new(),
+			prompt: None,
+			action: None,
+			filter_prompt: None,
+			scroll: 0,
+			page: 1,
+		}
+	}
+
+	/// Applies a keypress, returning false once the user asked to quit
+	pub fn handle_key(&mut self, key: Key) -> bool {
+		self.message = None;
+		if let Some((build, prompt)) = self.prompt.take() {
+			match (key, prompt) {
+				(Key::Ctrl('c'), _) => return false,
+				(Key::Char('y'), Prompt::ConfirmSignal(signal)) => self.action = Some((build, Action::Signal(signal))),
+				(_, Prompt::ConfirmSignal(_)) | (Key::Esc, _) => {}
+				// keys that don't pick anything leave the prompt open
+				(Key::Char(c), Prompt::Signal) => {
+					let signal = SIGNALS.iter().find(|&&(key, _)| key == c).map(|&(_, signal)| signal);
+					self.prompt = Some((build, signal.map_or(Prompt::Signal, Prompt::ConfirmSignal)));
+				}
+				(Key::Char(RESTORE_PRIORITY), Prompt::Priority) => self.action = Some((build, Action::RestorePriority)),
+				(Key::Char(c), Prompt::Priority) => match PRIORITIES.iter().find(|&&(key, ..)| key == c) {
+					Some(&(.., priority)) => self.action = Some((build, Action::Priority(priority))),
+					None => self.prompt = Some((build, Prompt::Priority)),
+				},
+				(_, prompt) => self.prompt = Some((build, prompt)),
+			}
+			return true;
+		}
+		if let Some(prompt) = &mut self.filter_prompt {
+			match key {
+				Key::Char('\n') => {
+					self.filter = prompt.clone();
+					self.filter_prompt = None;
+					self.scroll = 0;
+				}
+				Key::Esc => self.filter_prompt = None,
+				Key::Backspace => {
+					prompt.pop();
+				}
+				Key::Ctrl('c') => return false,
+				Key::Char(c) => prompt.push(c),
+				_ => {}
+			}
+			return true;
+		}
+
+		match key {
+			Key::Char('q') | Key::Ctrl('c') => return false,
+			Key::Char(' ') => self.paused = !self.paused,
+			Key::Char('s') => self.sort = self.sort.next(),
+			Key::Char('f') => self.full_paths = !self.full_paths,
+			Key::Char('/') => self.filter_prompt = Some(self.filter.clone()),
+			Key::Char('-') => self.tree_depth = Some(self.tree_depth.unwrap_or(self.tree_deepest).saturating_sub(1)),
+			Key::Char('+') | Key::Char('=') => {
+				self.tree_depth = self.tree_depth.map(|depth| depth + 1).filter(|&depth| depth < self.tree_deepest)
+			}
+			Key::Char('\t') => self.select(1),
+			Key::BackTab => self.select(self.builds.len().saturating_sub(1)),
+			Key::Char(']') => self.move_cursor(1),
+			Key::Char('[') => self.move_cursor(self.foldable.len().saturating_sub(1)),
+			Key::Char('\n') => self.toggle_fold(),
+			Key::Char('x') => self.prompt_for(Prompt::Signal),
+			Key::Char('p') => self.prompt_for(Prompt::Priority),
+			Key::Esc => self.filter.clear(),
+			Key::Up | Key::Char('k') => self.scroll = self.scroll.saturating_sub(1),
+			Key::Down | Key::Char('j') => self.scroll += 1,
+			Key::PageUp => self.scroll = self.scroll.saturating_sub(self.page),
+			Key::PageDown => self.scroll += self.page,
+			Key::Home | Key::Char('g') => self.scroll = 0,
+			Key::End | Key::Char('G') => self.scroll = usize::MAX,
+			_ => {}
+		}
+		true
+	}
+
+	/// Moves the selection `step` builds down the list, wrapping around, starting from the top if nothing listed is selected
+	fn select(&mut self, step: usize) {
+		if self.builds.is_empty() {
+			return;
+		}
+		let next = match self.builds.iter().position(|build| Some(build.id) == self.selected) {
+			Some(index) => (index + step) % self.builds.len(),
+			None if step == 1 => 0,
+			None => self.builds.len() - 1,
+		};
+		self.selected = Some(self.builds[next].id);
+		self.cursor = None;
+	}
+
+	/// Moves the cursor `step` processes down the selected build's tree like `select`
+	fn move_cursor(&mut self, step: usize) {
+		if self.foldable.is_empty() {
+			self.message = Some("select a build with processes to fold with tab first".to_string());
+			return;
+		}
+		let next = match self.foldable.iter().position(|&pid| Some(pid) == self.cursor) {
+			Some(index) => (index + step) % self.foldable.len(),
+			None if step == 1 => 0,
+			None => self.foldable.len() - 1,
+		};
+		self.cursor = Some(self.foldable[next]);
+	}
+
+	fn toggle_fold(&mut self) {
+		let (Some(id), Some(pid)) = (self.selected, self.cursor.filter(|pid| self.foldable.contains(pid))) else {
+			self.message = Some("pick a process with [ and ] first".to_string());
+			return;
+		};
+		let folded = self.folded.entry(id).or_default();
+		if !folded.remove(&pid) {
+			folded.insert(pid);
+		}
+	}
+
+	/// The selected build, if it's still listed
+	pub fn selected_build(&self) -> Option<&ListedBuild> {
+		self.builds.iter().find(|build| Some(build.id) == self.selected)
+	}
+
+	fn prompt_for(&mut self, prompt: Prompt) {
+		match self.selected_build() {
+			Some(build) => self.prompt = Some((build.clone(), prompt)),
+			None => self.message = Some("select a build with tab first".to_string()),
+		}
+	}
+
+	/// An action the user picked since the last call, for the main loop to carry out
+	pub fn take_action(&mut self) -> Option<(ListedBuild, Action)> {
+		self.action.take()
+	}
+
+	/// Keeps the title line in place, scrolls the rest and puts a help or filter prompt line at the bottom
+	pub fn frame(&mut self, lines: &[String], height: u16) -> Vec<String> {
+		let Some((title, body)) = lines.split_first() else {
+			return Vec::new();
+		};
+		self.page = (height as usize).saturating_sub(2).max(1);
+		self.scroll = self.scroll.min(body.len().saturating_sub(self.page));
+
+		let mut frame = vec![title.clone()];
+		frame.extend(body.iter().skip(self.scroll).take(self.page).cloned());
+		frame.resize(self.page + 1, String::new());
+		frame.push(self.status_line(body.len()));
+		frame
+	}
+
+	fn status_line(&self, body_len: usize) -> String {
+		if let Some(prompt) = &self.filter_prompt {
+			return format!("filter: {}▏ (enter to apply, esc to cancel)", prompt);
+		}
+		match &self.prompt {
+			Some((build, Prompt::Signal)) => {
+				let choices: Vec<_> = SIGNALS.iter().map(|(key, signal)| format!("{} {}", key, signal.as_str())).collect();
+				return format!("signal ({}) {}: {} · esc cancel", build.user, build.label, choices.join(" · "));
+			}
+			Some((build, Prompt::ConfirmSignal(signal))) => {
+				return format!(
+					"send {} to {} pids of ({}) {}? y to confirm, anything else cancels",
+					signal.as_str(),
+					build.pids,
+					build.user,
+					build.label
+				)
+			}
+			Some((build, Prompt::Priority)) => {
+				let mut choices: Vec<_> = PRIORITIES
+					.iter()
+					.map(|(key, name, priority)| format!("{} {} ({})", key, name, priority))
+					.collect();
+				if let Some(kept) = self.priorities.get(&build.id) {
+					choices.push(format!("{} as started ({})", RESTORE_PRIORITY, kept.original));
+				}
+				return format!("priority of ({}): {} · esc cancel", build.user, choices.join(" · "));
+			}
+			None => {}
+		}
+		if let Some(message) = &self.message {
+			return message.clone();
+		}
+
+		let mut status = vec![
+			"q quit".to_string(),
+			format!("space {}", if self.paused { "resume" } else { "pause" }),
+			format!("s sort: {}", self.sort.name()),
+			"f full paths".to_string(),
+			"/ filter".to_string(),
+			match self.tree_depth {
+				Some(depth) => format!("+/- tree depth {}", depth),
+				None => "+/- tree depth all".to_string(),
+			},
+		];
+		status.push(match self.selected_build() {
+			Some(build) if !self.foldable.is_empty() => {
+				format!("tab select ({}) · x signal · p priority · [ ] process · enter fold", build.user)
+			}
+			Some(build) => format!("tab select ({}) · x signal · p priority", build.user),
+			None => "tab select build".to_string(),
+		});
+		if !self.filter.is_empty() {
+			status.push(format!("esc clear filter \"{}\"", self.filter));
+		}
+		if body_len > self.page {
+			status.push(format!(
+				"↑↓ scroll {}-{}/{}",
+				self.scroll + 1,
+				(self.scroll + self.page).min(body_len),
+				body_len
+			));
+		}
+		if self.paused {
+			status.insert(0, "PAUSED".to_string());
+		}
+		status.join(" · ")
+	}
+}